@@ -0,0 +1,199 @@
+//! The only part of splash-damage that is given `cap_dac_override`. The
+//! daemon starts it with one end of a `SOCK_SEQPACKET` socket pair as stdin
+//! and, until it closes that, sends it paths one message at a time. Each is
+//! answered with a native-endian `i32` errno, 0 meaning the open file
+//! descriptor is attached as `SCM_RIGHTS`. Nothing but `/dev/uinput` and
+//! `/dev/input/event*` character devices is ever opened.
+
+use nix::errno::Errno;
+use nix::sys::socket::{ControlMessage, MsgFlags, SockType, getsockopt, recv, sendmsg, sockopt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::process::ExitCode;
+
+const INPUT_MAJOR: u32 = 13;
+const MISC_MAJOR: u32 = 10;
+const UINPUT_MINOR: u32 = 223;
+
+fn main() -> ExitCode {
+    let stdin = io::stdin();
+    let socket = stdin.as_fd();
+    if getsockopt(&socket, sockopt::SockType) != Ok(SockType::SeqPacket) {
+        eprintln!("splash-damage-helper is started by splash-damage, not run by hand");
+        return ExitCode::FAILURE;
+    }
+    match serve(socket, open_device) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("splash-damage-helper: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Answer requests on `socket` with `open` until the other end closes it.
+fn serve(socket: BorrowedFd, open: impl Fn(&[u8]) -> io::Result<File>) -> io::Result<()> {
+    let mut request = [0u8; 64];
+    loop {
+        let len = match recv(socket.as_raw_fd(), &mut request, MsgFlags::empty()) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let reply = open(&request[..len]);
+        let errno = match &reply {
+            Ok(_) => 0,
+            Err(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        };
+        let fds = reply.as_ref().map(|file| [file.as_raw_fd()]);
+        let cmsgs = match &fds {
+            Ok(fds) => vec![ControlMessage::ScmRights(fds)],
+            Err(_) => Vec::new(),
+        };
+        sendmsg::<()>(
+            socket.as_raw_fd(),
+            &[IoSlice::new(&errno.to_ne_bytes())],
+            &cmsgs,
+            MsgFlags::MSG_NOSIGNAL,
+            None,
+        )?;
+    }
+}
+
+/// Open `request` if it names a device splash-damage has any business with.
+fn open_device(request: &[u8]) -> io::Result<File> {
+    let uinput = request == b"/dev/uinput";
+    if !uinput && !is_event_node(request) {
+        return Err(Errno::EPERM.into());
+    }
+    let path = Path::new(std::str::from_utf8(request).map_err(|_| Errno::EPERM)?);
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC | libc::O_NOFOLLOW | libc::O_NOCTTY);
+    let file = options
+        .open(path)
+        .or_else(|_| options.write(false).open(path))?;
+
+    let metadata = file.metadata()?;
+    let rdev = metadata.rdev();
+    let expected = match uinput {
+        true => libc::major(rdev) == MISC_MAJOR && libc::minor(rdev) == UINPUT_MINOR,
+        false => libc::major(rdev) == INPUT_MAJOR,
+    };
+    if !metadata.file_type().is_char_device() || !expected {
+        return Err(Errno::EPERM.into());
+    }
+    Ok(file)
+}
+
+/// `/dev/input/event` followed by a number, and nothing else.
+fn is_event_node(request: &[u8]) -> bool {
+    request
+        .strip_prefix(b"/dev/input/event")
+        .is_some_and(|n| !n.is_empty() && n.iter().all(u8::is_ascii_digit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{
+        AddressFamily, ControlMessageOwned, SockFlag, recvmsg, send, socketpair,
+    };
+    use std::io::{IoSliceMut, Read, Seek, Write};
+    use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+    #[test]
+    fn only_event_nodes_and_uinput_are_opened() {
+        assert!(is_event_node(b"/dev/input/event0"));
+        assert!(is_event_node(b"/dev/input/event17"));
+        for request in [
+            &b"/dev/input/event"[..],
+            b"/dev/input/mice",
+            b"/dev/input/event0/../../../etc/shadow",
+            b"/dev/input/by-id/usb-kbd-event-kbd",
+            b"/etc/shadow",
+        ] {
+            assert!(!is_event_node(request), "{request:?}");
+            assert_eq!(
+                open_device(request).unwrap_err().raw_os_error(),
+                Some(libc::EPERM)
+            );
+        }
+    }
+
+    #[test]
+    fn replies_with_the_errno_and_the_descriptor() {
+        let (daemon, helper) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        let server = std::thread::spawn(move || {
+            serve(helper.as_fd(), |request| match request {
+                b"good" => {
+                    let mut file = tempfile()?;
+                    file.write_all(b"hello")?;
+                    file.rewind()?;
+                    Ok(file)
+                }
+                _ => Err(Errno::ENOENT.into()),
+            })
+        });
+
+        let ask = |request: &[u8]| {
+            send(daemon.as_raw_fd(), request, MsgFlags::empty()).unwrap();
+            let mut errno = [0u8; 4];
+            let mut cmsg = nix::cmsg_space!(RawFd);
+            let fd = {
+                let mut iov = [IoSliceMut::new(&mut errno)];
+                let msg = recvmsg::<()>(
+                    daemon.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg),
+                    MsgFlags::empty(),
+                )
+                .unwrap();
+                msg.cmsgs().unwrap().find_map(|cmsg| match cmsg {
+                    // SAFETY: the descriptor was just received and is ours.
+                    ControlMessageOwned::ScmRights(fds) => {
+                        Some(unsafe { OwnedFd::from_raw_fd(fds[0]) })
+                    }
+                    _ => None,
+                })
+            };
+            (i32::from_ne_bytes(errno), fd)
+        };
+
+        let (errno, fd) = ask(b"bad");
+        assert_eq!(errno, libc::ENOENT);
+        assert!(fd.is_none());
+
+        let (errno, fd) = ask(b"good");
+        assert_eq!(errno, 0);
+        let mut contents = String::new();
+        File::from(fd.unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "hello");
+
+        drop(daemon);
+        server.join().unwrap().unwrap();
+    }
+
+    /// An unnamed file that goes away with its last descriptor.
+    fn tempfile() -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .open(std::env::temp_dir())
+    }
+}
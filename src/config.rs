@@ -1,19 +1,120 @@
-use evdev::Key;
+use crate::emergency::EmergencyChord;
+use crate::layout::Layout;
+use crate::mousekeys::{Action, MouseKeysConfig};
+use evdev::{KeyCode, RelativeAxisCode};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub copilot_as_meta: bool,
+    #[serde(default)]
+    pub metrics: bool,
+    #[serde(default)]
+    pub low_latency: bool,
+    pub realtime_priority: Option<i32>,
+    #[serde(default)]
+    pub mice: bool,
+    #[serde(default)]
+    pub strict: bool,
+    pub layout: Option<String>,
+    pub copy_device_id: Option<bool>,
+    #[serde(default)]
+    pub sandbox: bool,
+    #[serde(default)]
+    pub secure_input: Vec<String>,
+    #[serde(default)]
+    pub secure_input_titles: Vec<String>,
     #[serde(rename = "remap")]
     pub remaps: Vec<RemapEntry>,
+    #[serde(default, rename = "hotstring")]
+    pub hotstrings: Vec<HotstringEntry>,
+    #[serde(default)]
+    pub emergency: Option<toml::Value>,
+    pub mousekeys: Option<MouseKeysEntry>,
+}
+
+/// `[mousekeys]`; every binding has a default around the home row.
+#[derive(Debug, Deserialize)]
+pub struct MouseKeysEntry {
+    pub layer: String,
+    pub up: Option<String>,
+    pub down: Option<String>,
+    pub left: Option<String>,
+    pub right: Option<String>,
+    pub left_click: Option<String>,
+    pub right_click: Option<String>,
+    pub middle_click: Option<String>,
+    pub scroll_up: Option<String>,
+    pub scroll_down: Option<String>,
+    pub scroll_left: Option<String>,
+    pub scroll_right: Option<String>,
+    pub start_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    pub acceleration_ms: Option<u64>,
+    pub curve: Option<f64>,
+    pub scroll_rate: Option<f64>,
 }
 
 pub struct LoadedConfig {
     pub rules: Vec<RemapRule>,
     pub copilot_as_meta: bool,
+    pub metrics: bool,
+    pub low_latency: bool,
+    pub realtime_priority: Option<i32>,
+    pub mice: bool,
+    /// Virtual devices present themselves with their source's bus, vendor and product
+    pub copy_device_id: bool,
+    pub sandbox: bool,
+    pub secure_input: SecureInput,
+    pub emergency: EmergencyChord,
+    pub mousekeys: Option<MouseKeysConfig>,
+    pub hotstrings: Vec<Hotstring>,
+}
+
+/// Windows no rule applies in and no keystroke is logged from, such as
+/// password prompts.
+#[derive(Debug, Clone, Default)]
+pub struct SecureInput {
+    /// Window classes, matched like `include` and `exclude`
+    pub classes: Vec<String>,
+    /// Matched anywhere in the window title, ignoring ASCII case
+    pub titles: Vec<String>,
+}
+
+impl SecureInput {
+    pub fn matches(&self, resource_class: &str, caption: &str) -> bool {
+        if self
+            .classes
+            .iter()
+            .any(|class| resource_class.eq_ignore_ascii_case(class))
+        {
+            return true;
+        }
+        self.titles.iter().any(|title| {
+            caption
+                .as_bytes()
+                .windows(title.len())
+                .any(|part| part.eq_ignore_ascii_case(title.as_bytes()))
+        })
+    }
+}
+
+/// `[[hotstring]]`: typing `abbr` then space types `text` instead.
+#[derive(Debug, Deserialize)]
+pub struct HotstringEntry {
+    pub abbr: String,
+    pub text: String,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -24,12 +125,37 @@ pub struct RemapEntry {
     pub include: Vec<String>,
     #[serde(default)]
     pub exclude: Vec<String>,
+    #[serde(alias = "exact_modifiers")]
+    pub strict: Option<bool>,
+    #[serde(default)]
+    pub passthrough: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifier {
+    /// Keycode emitted for this modifier; the left one for generic names
+    pub key: KeyCode,
+    /// Generic names (`ctrl`) match either side, sided ones (`rctrl`) only their own
+    pub either_side: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct KeyCombo {
-    pub modifiers: Vec<Key>,
-    pub key: Key,
+    pub modifiers: Vec<Modifier>,
+    pub key: KeyCode,
+    /// Normalised name, shared cheaply with `ctl watch` and metrics.
+    pub name: Arc<str>,
+}
+
+/// A key and whether it is typed with shift held.
+pub type TypedKey = (KeyCode, bool);
+
+#[derive(Debug, Clone)]
+pub struct Hotstring {
+    pub abbr: Vec<TypedKey>,
+    pub text: Vec<TypedKey>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -38,23 +164,106 @@ pub struct RemapRule {
     pub to: KeyCombo,
     pub include: Vec<String>,
     pub exclude: Vec<String>,
+    /// Don't fire when modifiers other than `from`'s (and `passthrough`) are held
+    pub strict: bool,
+    /// Extra modifiers a strict rule tolerates, left held through the remap
+    pub passthrough: Vec<Modifier>,
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{modifier}+")?;
+        }
+        write!(f, "{}", key_name(self.key))
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = match self.key {
+            _ if self.either_side => "",
+            KeyCode::KEY_LEFTCTRL
+            | KeyCode::KEY_LEFTSHIFT
+            | KeyCode::KEY_LEFTALT
+            | KeyCode::KEY_LEFTMETA => "l",
+            KeyCode::KEY_RIGHTCTRL
+            | KeyCode::KEY_RIGHTSHIFT
+            | KeyCode::KEY_RIGHTALT
+            | KeyCode::KEY_RIGHTMETA => "r",
+            _ => "",
+        };
+        write!(f, "{side}{}", key_name(self.key))
+    }
+}
+
+/// Scroll wheel notches, as pseudo-keys so rules can use them like buttons.
+/// The codes are unassigned in linux/input-event-codes.h.
+pub const WHEEL_UP: KeyCode = KeyCode::new(0x2fc);
+pub const WHEEL_DOWN: KeyCode = KeyCode::new(0x2fd);
+pub const WHEEL_LEFT: KeyCode = KeyCode::new(0x2fe);
+pub const WHEEL_RIGHT: KeyCode = KeyCode::new(0x2ff);
+
+/// Human-readable name for a key, matching the names accepted in the config.
+pub fn key_name(key: KeyCode) -> String {
+    match key {
+        WHEEL_UP => return "wheelup".to_string(),
+        WHEEL_DOWN => return "wheeldown".to_string(),
+        WHEEL_LEFT => return "wheelleft".to_string(),
+        WHEEL_RIGHT => return "wheelright".to_string(),
+        KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL => return "ctrl".to_string(),
+        KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT => return "shift".to_string(),
+        KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT => return "alt".to_string(),
+        KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA => return "super".to_string(),
+        _ => {}
+    }
+    if let Some((name, _)) = KEYNAME_MAP
+        .iter()
+        .find(|(name, k)| **k == key && name.len() == 1)
+    {
+        return name.to_string();
+    }
+    let debug = format!("{key:?}");
+    if KeyCode::from_str(&debug).is_err() {
+        return format!("code:{}", key.code());
+    }
+    debug.strip_prefix("KEY_").unwrap_or(&debug).to_lowercase()
 }
 
 pub fn load_config(path: &Path) -> anyhow::Result<LoadedConfig> {
-    let content = std::fs::read_to_string(path)?;
-    let config: Config = toml::from_str(&content)?;
+    parse_config(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse_config(content: &str) -> anyhow::Result<LoadedConfig> {
+    let config: Config = toml::from_str(content)?;
+    let layout = config.layout.as_deref().map(Layout::new).transpose()?;
+    if let Some(priority) = config.realtime_priority
+        && !(1..=99).contains(&priority)
+    {
+        anyhow::bail!("realtime_priority must be between 1 and 99, not {priority}");
+    }
+    if config.realtime_priority.is_some() && !config.low_latency {
+        anyhow::bail!("realtime_priority only applies with low_latency = true");
+    }
 
     let rules = config
         .remaps
         .into_iter()
         .map(|entry| {
-            let from = parse_key_combo(&entry.from)?;
-            let to = parse_key_combo(&entry.to)?;
+            let from = parse_key_combo(&entry.from, layout.as_ref())?;
+            let to = parse_key_combo(&entry.to, layout.as_ref())?;
+            let passthrough = entry
+                .passthrough
+                .iter()
+                .map(|m| parse_modifier(m))
+                .collect::<anyhow::Result<_>>()?;
             Ok(RemapRule {
                 from,
                 to,
                 include: entry.include,
                 exclude: entry.exclude,
+                strict: entry.strict.unwrap_or(config.strict),
+                passthrough,
             })
         })
         .collect::<anyhow::Result<Vec<_>>>()?;
@@ -62,11 +271,141 @@ pub fn load_config(path: &Path) -> anyhow::Result<LoadedConfig> {
     Ok(LoadedConfig {
         rules,
         copilot_as_meta: config.copilot_as_meta,
+        metrics: config.metrics,
+        low_latency: config.low_latency,
+        realtime_priority: config.realtime_priority,
+        mice: config.mice,
+        copy_device_id: config.copy_device_id.unwrap_or(true),
+        sandbox: config.sandbox,
+        secure_input: SecureInput {
+            classes: config.secure_input,
+            titles: config
+                .secure_input_titles
+                .into_iter()
+                .filter(|title| !title.is_empty())
+                .collect(),
+        },
+        emergency: EmergencyChord::from_value(config.emergency.as_ref()),
+        mousekeys: config
+            .mousekeys
+            .map(|entry| load_mousekeys(entry, layout.as_ref()))
+            .transpose()?,
+        hotstrings: config
+            .hotstrings
+            .into_iter()
+            .map(|entry| {
+                if entry.abbr.is_empty() {
+                    anyhow::bail!("hotstring with an empty abbr");
+                }
+                Ok(Hotstring {
+                    abbr: typed_keys(&entry.abbr, layout.as_ref())?,
+                    text: typed_keys(&entry.text, layout.as_ref())?,
+                    include: entry.include,
+                    exclude: entry.exclude,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?,
     })
 }
 
-fn parse_key_combo(s: &str) -> anyhow::Result<KeyCombo> {
-    let parts: Vec<&str> = s.split('+').map(str::trim).collect();
+/// The keys that type `text`.
+fn typed_keys(text: &str, layout: Option<&Layout>) -> anyhow::Result<Vec<TypedKey>> {
+    text.chars()
+        .map(|ch| match (ch, layout) {
+            (' ', _) => Ok((KeyCode::KEY_SPACE, false)),
+            ('\n', _) => Ok((KeyCode::KEY_ENTER, false)),
+            ('\t', _) => Ok((KeyCode::KEY_TAB, false)),
+            // The US key for it would type something else on this layout
+            (_, Some(layout)) => layout
+                .key_for(ch)
+                .ok_or_else(|| anyhow::anyhow!("no key types {ch:?} in {text:?}")),
+            _ if ch.is_ascii_uppercase() => {
+                parse_key(&ch.to_string(), None).map(|(key, _)| (key, true))
+            }
+            _ if ch.is_ascii_graphic() => parse_key(&ch.to_string(), None),
+            _ => anyhow::bail!("no key types {ch:?} in {text:?}"),
+        })
+        .collect()
+}
+
+fn load_mousekeys(
+    entry: MouseKeysEntry,
+    layout: Option<&Layout>,
+) -> anyhow::Result<MouseKeysConfig> {
+    let vertical = (
+        RelativeAxisCode::REL_WHEEL,
+        RelativeAxisCode::REL_WHEEL_HI_RES,
+    );
+    let horizontal = (
+        RelativeAxisCode::REL_HWHEEL,
+        RelativeAxisCode::REL_HWHEEL_HI_RES,
+    );
+    let scroll = |(axis, hi_res), direction| Action::Scroll {
+        axis,
+        hi_res,
+        direction,
+    };
+    let bindings = [
+        (entry.up, Some("i"), Action::Move { x: 0, y: -1 }),
+        (entry.down, Some("k"), Action::Move { x: 0, y: 1 }),
+        (entry.left, Some("j"), Action::Move { x: -1, y: 0 }),
+        (entry.right, Some("l"), Action::Move { x: 1, y: 0 }),
+        (
+            entry.left_click,
+            Some("u"),
+            Action::Button(KeyCode::BTN_LEFT),
+        ),
+        (
+            entry.right_click,
+            Some("o"),
+            Action::Button(KeyCode::BTN_RIGHT),
+        ),
+        (
+            entry.middle_click,
+            None,
+            Action::Button(KeyCode::BTN_MIDDLE),
+        ),
+        (entry.scroll_up, Some("y"), scroll(vertical, 1)),
+        (entry.scroll_down, Some("h"), scroll(vertical, -1)),
+        (entry.scroll_left, None, scroll(horizontal, -1)),
+        (entry.scroll_right, None, scroll(horizontal, 1)),
+    ]
+    .into_iter()
+    .filter_map(|(name, default, action)| Some((name.or(default.map(String::from))?, action)))
+    .map(|(name, action)| Ok((parse_key(&name, layout)?.0, action)))
+    .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+    let positive = |name, value: Option<f64>, default| match value.unwrap_or(default) {
+        value if value.is_finite() && value > 0.0 => Ok(value),
+        value => anyhow::bail!("mousekeys {name} must be a positive number, not {value}"),
+    };
+    let start_speed = positive("start_speed", entry.start_speed, 200.0)?;
+    let max_speed = positive("max_speed", entry.max_speed, 1500.0_f64.max(start_speed))?;
+    if max_speed < start_speed {
+        anyhow::bail!("mousekeys max_speed {max_speed} is below start_speed {start_speed}");
+    }
+
+    Ok(MouseKeysConfig {
+        layer: parse_key(&entry.layer, layout)?.0,
+        bindings,
+        start_speed,
+        max_speed,
+        acceleration: Duration::from_millis(entry.acceleration_ms.unwrap_or(600)),
+        curve: positive("curve", entry.curve, 2.0)?,
+        scroll_rate: positive("scroll_rate", entry.scroll_rate, 15.0)?,
+    })
+}
+
+/// Parse a combo as a flat list of keys that must all be held.
+pub fn parse_chord(s: &str) -> anyhow::Result<Vec<KeyCode>> {
+    let combo = parse_key_combo(s, None)?;
+    let mut keys: Vec<KeyCode> = combo.modifiers.iter().map(|m| m.key).collect();
+    keys.push(combo.key);
+    Ok(keys)
+}
+
+fn parse_key_combo(s: &str, layout: Option<&Layout>) -> anyhow::Result<KeyCombo> {
+    let parts = split_combo(s);
     if parts.is_empty() {
         anyhow::bail!("empty key combo");
     }
@@ -76,129 +415,458 @@ fn parse_key_combo(s: &str) -> anyhow::Result<KeyCombo> {
         modifiers.push(parse_modifier(part)?);
     }
 
-    let key = parse_key(parts.last().unwrap())?;
-    Ok(KeyCombo { modifiers, key })
+    let (key, shifted) = parse_key(parts.last().unwrap(), layout)?;
+    if shifted && !modifiers.iter().any(|m| m.key == KeyCode::KEY_LEFTSHIFT) {
+        modifiers.push(Modifier {
+            key: KeyCode::KEY_LEFTSHIFT,
+            either_side: true,
+        });
+    }
+    let mut combo = KeyCombo {
+        modifiers,
+        key,
+        name: Arc::from(""),
+    };
+    combo.name = combo.to_string().into();
+    Ok(combo)
 }
 
-fn parse_modifier(s: &str) -> anyhow::Result<Key> {
-    match s.to_lowercase().as_str() {
-        "ctrl" | "control" => Ok(Key::KEY_LEFTCTRL),
-        "shift" => Ok(Key::KEY_LEFTSHIFT),
-        "alt" => Ok(Key::KEY_LEFTALT),
-        "super" | "meta" | "cmd" => Ok(Key::KEY_LEFTMETA),
-        "capslock" => Ok(Key::KEY_CAPSLOCK),
-        other => anyhow::bail!("unknown modifier: {other}"),
+/// Split a combo on `+`, where `\+` is a literal plus key.
+fn split_combo(s: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'+') => {
+                chars.next();
+                parts.last_mut().unwrap().push('+');
+            }
+            '+' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
     }
+    parts.iter().map(|p| p.trim().to_string()).collect()
+}
+
+fn parse_modifier(s: &str) -> anyhow::Result<Modifier> {
+    let (key, either_side) = match s.to_lowercase().as_str() {
+        "ctrl" | "control" => (KeyCode::KEY_LEFTCTRL, true),
+        "shift" => (KeyCode::KEY_LEFTSHIFT, true),
+        "alt" => (KeyCode::KEY_LEFTALT, true),
+        "super" | "meta" | "cmd" => (KeyCode::KEY_LEFTMETA, true),
+        "lctrl" | "leftctrl" => (KeyCode::KEY_LEFTCTRL, false),
+        "rctrl" | "rightctrl" => (KeyCode::KEY_RIGHTCTRL, false),
+        "lshift" | "leftshift" => (KeyCode::KEY_LEFTSHIFT, false),
+        "rshift" | "rightshift" => (KeyCode::KEY_RIGHTSHIFT, false),
+        "lalt" | "leftalt" => (KeyCode::KEY_LEFTALT, false),
+        "ralt" | "rightalt" | "altgr" => (KeyCode::KEY_RIGHTALT, false),
+        "lsuper" | "lmeta" | "leftmeta" => (KeyCode::KEY_LEFTMETA, false),
+        "rsuper" | "rmeta" | "rightmeta" => (KeyCode::KEY_RIGHTMETA, false),
+        "capslock" => (KeyCode::KEY_CAPSLOCK, false),
+        other => anyhow::bail!("unknown modifier: {other}"),
+    };
+    Ok(Modifier { key, either_side })
 }
 
-fn parse_key(s: &str) -> anyhow::Result<Key> {
+/// Parse the final key of a combo, and whether it needs shift to type.
+fn parse_key(s: &str, layout: Option<&Layout>) -> anyhow::Result<(KeyCode, bool)> {
     if let Ok(modifier) = parse_modifier(s) {
-        return Ok(modifier);
+        return Ok((modifier.key, false));
+    }
+    let s = if s.eq_ignore_ascii_case("plus") {
+        "+"
+    } else {
+        s
+    };
+    let mut chars = s.chars().flat_map(char::to_lowercase);
+    if let (Some(ch), None) = (chars.next(), chars.next()) {
+        // Digits stay on the number row: on AZERTY they are shifted, and
+        // `super+1` gaining a shift would stop matching anything.
+        if let Some(layout) = layout
+            && !ch.is_ascii_digit()
+        {
+            if let Some(found) = layout.key_for(ch) {
+                return Ok(found);
+            }
+            // The US key for a symbol would type something else here
+            if !ch.is_alphanumeric() {
+                anyhow::bail!("no key types {ch:?} on this layout without AltGr");
+            }
+        }
+        if let Some((_, key)) = SHIFTED_SYMBOLS.iter().find(|(symbol, _)| *symbol == ch) {
+            return Ok((*key, true));
+        }
+    }
+    if let Some(key) = KEYNAME_MAP.get(s.to_lowercase().as_str()) {
+        return Ok((*key, false));
     }
-    KEYNAME_MAP
-        .get(s.to_lowercase().as_str())
-        .copied()
-        .ok_or_else(|| anyhow::anyhow!("unknown key: {s}"))
+    if let Some(code) = s.strip_prefix("code:") {
+        let code: u16 = code
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid key code: {s}"))?;
+        if code > KEY_CODE_MAX {
+            anyhow::bail!("key code out of range: {s}");
+        }
+        return Ok((KeyCode::new(code), false));
+    }
+    // Anything else evdev knows by name: KEY_VOLUMEUP, volumeup, BTN_LEFT...
+    let upper = s.to_uppercase();
+    KeyCode::from_str(&upper)
+        .or_else(|_| KeyCode::from_str(&format!("KEY_{upper}")))
+        .map(|key| (key, false))
+        .map_err(|_| anyhow::anyhow!("unknown key: {s}"))
 }
 
-static KEYNAME_MAP: std::sync::LazyLock<HashMap<&'static str, Key>> =
+/// Symbols typed with shift on US QWERTY, and the key they are on.
+const SHIFTED_SYMBOLS: &[(char, KeyCode)] = &[
+    ('!', KeyCode::KEY_1),
+    ('@', KeyCode::KEY_2),
+    ('#', KeyCode::KEY_3),
+    ('$', KeyCode::KEY_4),
+    ('%', KeyCode::KEY_5),
+    ('^', KeyCode::KEY_6),
+    ('&', KeyCode::KEY_7),
+    ('*', KeyCode::KEY_8),
+    ('(', KeyCode::KEY_9),
+    (')', KeyCode::KEY_0),
+    ('_', KeyCode::KEY_MINUS),
+    ('+', KeyCode::KEY_EQUAL),
+    ('{', KeyCode::KEY_LEFTBRACE),
+    ('}', KeyCode::KEY_RIGHTBRACE),
+    ('|', KeyCode::KEY_BACKSLASH),
+    (':', KeyCode::KEY_SEMICOLON),
+    ('"', KeyCode::KEY_APOSTROPHE),
+    ('<', KeyCode::KEY_COMMA),
+    ('>', KeyCode::KEY_DOT),
+    ('?', KeyCode::KEY_SLASH),
+    ('~', KeyCode::KEY_GRAVE),
+];
+
+/// Below `KEY_MAX` from linux/input-event-codes.h, which is 0x2ff: the last
+/// four codes are the wheel pseudo-keys, so `code:` can't name those.
+const KEY_CODE_MAX: u16 = WHEEL_UP.code() - 1;
+
+static KEYNAME_MAP: std::sync::LazyLock<HashMap<&'static str, KeyCode>> =
     std::sync::LazyLock::new(|| {
         let mut m = HashMap::new();
-        m.insert("a", Key::KEY_A);
-        m.insert("b", Key::KEY_B);
-        m.insert("c", Key::KEY_C);
-        m.insert("d", Key::KEY_D);
-        m.insert("e", Key::KEY_E);
-        m.insert("f", Key::KEY_F);
-        m.insert("g", Key::KEY_G);
-        m.insert("h", Key::KEY_H);
-        m.insert("i", Key::KEY_I);
-        m.insert("j", Key::KEY_J);
-        m.insert("k", Key::KEY_K);
-        m.insert("l", Key::KEY_L);
-        m.insert("m", Key::KEY_M);
-        m.insert("n", Key::KEY_N);
-        m.insert("o", Key::KEY_O);
-        m.insert("p", Key::KEY_P);
-        m.insert("q", Key::KEY_Q);
-        m.insert("r", Key::KEY_R);
-        m.insert("s", Key::KEY_S);
-        m.insert("t", Key::KEY_T);
-        m.insert("u", Key::KEY_U);
-        m.insert("v", Key::KEY_V);
-        m.insert("w", Key::KEY_W);
-        m.insert("x", Key::KEY_X);
-        m.insert("y", Key::KEY_Y);
-        m.insert("z", Key::KEY_Z);
-        m.insert("0", Key::KEY_0);
-        m.insert("1", Key::KEY_1);
-        m.insert("2", Key::KEY_2);
-        m.insert("3", Key::KEY_3);
-        m.insert("4", Key::KEY_4);
-        m.insert("5", Key::KEY_5);
-        m.insert("6", Key::KEY_6);
-        m.insert("7", Key::KEY_7);
-        m.insert("8", Key::KEY_8);
-        m.insert("9", Key::KEY_9);
-        m.insert("capslock", Key::KEY_CAPSLOCK);
-        m.insert(".", Key::KEY_DOT);
-        m.insert("dot", Key::KEY_DOT);
-        m.insert(",", Key::KEY_COMMA);
-        m.insert("comma", Key::KEY_COMMA);
-        m.insert("/", Key::KEY_SLASH);
-        m.insert("slash", Key::KEY_SLASH);
-        m.insert(";", Key::KEY_SEMICOLON);
-        m.insert("semicolon", Key::KEY_SEMICOLON);
-        m.insert("'", Key::KEY_APOSTROPHE);
-        m.insert("apostrophe", Key::KEY_APOSTROPHE);
-        m.insert("[", Key::KEY_LEFTBRACE);
-        m.insert("]", Key::KEY_RIGHTBRACE);
-        m.insert("\\", Key::KEY_BACKSLASH);
-        m.insert("backslash", Key::KEY_BACKSLASH);
-        m.insert("-", Key::KEY_MINUS);
-        m.insert("minus", Key::KEY_MINUS);
-        m.insert("=", Key::KEY_EQUAL);
-        m.insert("equal", Key::KEY_EQUAL);
-        m.insert("`", Key::KEY_GRAVE);
-        m.insert("grave", Key::KEY_GRAVE);
-        m.insert("space", Key::KEY_SPACE);
-        m.insert("enter", Key::KEY_ENTER);
-        m.insert("tab", Key::KEY_TAB);
-        m.insert("escape", Key::KEY_ESC);
-        m.insert("esc", Key::KEY_ESC);
-        m.insert("backspace", Key::KEY_BACKSPACE);
-        m.insert("delete", Key::KEY_DELETE);
-        m.insert("up", Key::KEY_UP);
-        m.insert("down", Key::KEY_DOWN);
-        m.insert("left", Key::KEY_LEFT);
-        m.insert("right", Key::KEY_RIGHT);
-        m.insert("home", Key::KEY_HOME);
-        m.insert("end", Key::KEY_END);
-        m.insert("pageup", Key::KEY_PAGEUP);
-        m.insert("pagedown", Key::KEY_PAGEDOWN);
-        m.insert("f1", Key::KEY_F1);
-        m.insert("f2", Key::KEY_F2);
-        m.insert("f3", Key::KEY_F3);
-        m.insert("f4", Key::KEY_F4);
-        m.insert("f5", Key::KEY_F5);
-        m.insert("f6", Key::KEY_F6);
-        m.insert("f7", Key::KEY_F7);
-        m.insert("f8", Key::KEY_F8);
-        m.insert("f9", Key::KEY_F9);
-        m.insert("f10", Key::KEY_F10);
-        m.insert("f11", Key::KEY_F11);
-        m.insert("f12", Key::KEY_F12);
-        m.insert("f13", Key::KEY_F13);
-        m.insert("f14", Key::KEY_F14);
-        m.insert("f15", Key::KEY_F15);
-        m.insert("f16", Key::KEY_F16);
-        m.insert("f17", Key::KEY_F17);
-        m.insert("f18", Key::KEY_F18);
-        m.insert("f19", Key::KEY_F19);
-        m.insert("f20", Key::KEY_F20);
-        m.insert("f21", Key::KEY_F21);
-        m.insert("f22", Key::KEY_F22);
-        m.insert("f23", Key::KEY_F23);
-        m.insert("f24", Key::KEY_F24);
+        m.insert("a", KeyCode::KEY_A);
+        m.insert("b", KeyCode::KEY_B);
+        m.insert("c", KeyCode::KEY_C);
+        m.insert("d", KeyCode::KEY_D);
+        m.insert("e", KeyCode::KEY_E);
+        m.insert("f", KeyCode::KEY_F);
+        m.insert("g", KeyCode::KEY_G);
+        m.insert("h", KeyCode::KEY_H);
+        m.insert("i", KeyCode::KEY_I);
+        m.insert("j", KeyCode::KEY_J);
+        m.insert("k", KeyCode::KEY_K);
+        m.insert("l", KeyCode::KEY_L);
+        m.insert("m", KeyCode::KEY_M);
+        m.insert("n", KeyCode::KEY_N);
+        m.insert("o", KeyCode::KEY_O);
+        m.insert("p", KeyCode::KEY_P);
+        m.insert("q", KeyCode::KEY_Q);
+        m.insert("r", KeyCode::KEY_R);
+        m.insert("s", KeyCode::KEY_S);
+        m.insert("t", KeyCode::KEY_T);
+        m.insert("u", KeyCode::KEY_U);
+        m.insert("v", KeyCode::KEY_V);
+        m.insert("w", KeyCode::KEY_W);
+        m.insert("x", KeyCode::KEY_X);
+        m.insert("y", KeyCode::KEY_Y);
+        m.insert("z", KeyCode::KEY_Z);
+        m.insert("0", KeyCode::KEY_0);
+        m.insert("1", KeyCode::KEY_1);
+        m.insert("2", KeyCode::KEY_2);
+        m.insert("3", KeyCode::KEY_3);
+        m.insert("4", KeyCode::KEY_4);
+        m.insert("5", KeyCode::KEY_5);
+        m.insert("6", KeyCode::KEY_6);
+        m.insert("7", KeyCode::KEY_7);
+        m.insert("8", KeyCode::KEY_8);
+        m.insert("9", KeyCode::KEY_9);
+        m.insert("capslock", KeyCode::KEY_CAPSLOCK);
+        m.insert(".", KeyCode::KEY_DOT);
+        m.insert("dot", KeyCode::KEY_DOT);
+        m.insert(",", KeyCode::KEY_COMMA);
+        m.insert("comma", KeyCode::KEY_COMMA);
+        m.insert("/", KeyCode::KEY_SLASH);
+        m.insert("slash", KeyCode::KEY_SLASH);
+        m.insert(";", KeyCode::KEY_SEMICOLON);
+        m.insert("semicolon", KeyCode::KEY_SEMICOLON);
+        m.insert("'", KeyCode::KEY_APOSTROPHE);
+        m.insert("apostrophe", KeyCode::KEY_APOSTROPHE);
+        m.insert("[", KeyCode::KEY_LEFTBRACE);
+        m.insert("]", KeyCode::KEY_RIGHTBRACE);
+        m.insert("\\", KeyCode::KEY_BACKSLASH);
+        m.insert("backslash", KeyCode::KEY_BACKSLASH);
+        m.insert("-", KeyCode::KEY_MINUS);
+        m.insert("minus", KeyCode::KEY_MINUS);
+        m.insert("=", KeyCode::KEY_EQUAL);
+        m.insert("equal", KeyCode::KEY_EQUAL);
+        m.insert("`", KeyCode::KEY_GRAVE);
+        m.insert("grave", KeyCode::KEY_GRAVE);
+        m.insert("space", KeyCode::KEY_SPACE);
+        m.insert("enter", KeyCode::KEY_ENTER);
+        m.insert("tab", KeyCode::KEY_TAB);
+        m.insert("escape", KeyCode::KEY_ESC);
+        m.insert("esc", KeyCode::KEY_ESC);
+        m.insert("backspace", KeyCode::KEY_BACKSPACE);
+        m.insert("delete", KeyCode::KEY_DELETE);
+        m.insert("up", KeyCode::KEY_UP);
+        m.insert("down", KeyCode::KEY_DOWN);
+        m.insert("left", KeyCode::KEY_LEFT);
+        m.insert("right", KeyCode::KEY_RIGHT);
+        m.insert("home", KeyCode::KEY_HOME);
+        m.insert("end", KeyCode::KEY_END);
+        m.insert("pageup", KeyCode::KEY_PAGEUP);
+        m.insert("pagedown", KeyCode::KEY_PAGEDOWN);
+        m.insert("f1", KeyCode::KEY_F1);
+        m.insert("f2", KeyCode::KEY_F2);
+        m.insert("f3", KeyCode::KEY_F3);
+        m.insert("f4", KeyCode::KEY_F4);
+        m.insert("f5", KeyCode::KEY_F5);
+        m.insert("f6", KeyCode::KEY_F6);
+        m.insert("f7", KeyCode::KEY_F7);
+        m.insert("f8", KeyCode::KEY_F8);
+        m.insert("f9", KeyCode::KEY_F9);
+        m.insert("f10", KeyCode::KEY_F10);
+        m.insert("f11", KeyCode::KEY_F11);
+        m.insert("f12", KeyCode::KEY_F12);
+        m.insert("f13", KeyCode::KEY_F13);
+        m.insert("f14", KeyCode::KEY_F14);
+        m.insert("f15", KeyCode::KEY_F15);
+        m.insert("f16", KeyCode::KEY_F16);
+        m.insert("f17", KeyCode::KEY_F17);
+        m.insert("f18", KeyCode::KEY_F18);
+        m.insert("f19", KeyCode::KEY_F19);
+        m.insert("f20", KeyCode::KEY_F20);
+        m.insert("f21", KeyCode::KEY_F21);
+        m.insert("f22", KeyCode::KEY_F22);
+        m.insert("f23", KeyCode::KEY_F23);
+        m.insert("f24", KeyCode::KEY_F24);
+        m.insert("insert", KeyCode::KEY_INSERT);
+        m.insert("ins", KeyCode::KEY_INSERT);
+        m.insert("del", KeyCode::KEY_DELETE);
+        m.insert("pgup", KeyCode::KEY_PAGEUP);
+        m.insert("pgdn", KeyCode::KEY_PAGEDOWN);
+        m.insert("printscreen", KeyCode::KEY_SYSRQ);
+        m.insert("prtsc", KeyCode::KEY_SYSRQ);
+        m.insert("return", KeyCode::KEY_ENTER);
+        m.insert("volup", KeyCode::KEY_VOLUMEUP);
+        m.insert("voldown", KeyCode::KEY_VOLUMEDOWN);
+        m.insert("mouseleft", KeyCode::BTN_LEFT);
+        m.insert("mouseright", KeyCode::BTN_RIGHT);
+        m.insert("mousemiddle", KeyCode::BTN_MIDDLE);
+        m.insert("mouseback", KeyCode::BTN_SIDE);
+        m.insert("mouseforward", KeyCode::BTN_EXTRA);
+        m.insert("wheelup", WHEEL_UP);
+        m.insert("wheeldown", WHEEL_DOWN);
+        m.insert("wheelleft", WHEEL_LEFT);
+        m.insert("wheelright", WHEEL_RIGHT);
         m
     });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emergency_that_is_not_a_table_falls_back_to_the_default() {
+        let config =
+            parse_config("emergency = \"ctrl+alt+esc\"\n[[remap]]\nfrom = \"a\"\nto = \"b\"\n")
+                .unwrap();
+        assert_eq!(config.emergency.keys, EmergencyChord::default().keys);
+    }
+
+    #[test]
+    fn key_names_follow_evdev_and_the_aliases() {
+        let key = |s| parse_key(s, None).unwrap();
+        assert_eq!(key("z"), (KeyCode::KEY_Z, false));
+        assert_eq!(key("Z"), (KeyCode::KEY_Z, false));
+        assert_eq!(key("volumeup"), (KeyCode::KEY_VOLUMEUP, false));
+        assert_eq!(key("KEY_VOLUMEUP"), (KeyCode::KEY_VOLUMEUP, false));
+        assert_eq!(key("btn_side"), (KeyCode::BTN_SIDE, false));
+        assert_eq!(key("prtsc"), (KeyCode::KEY_SYSRQ, false));
+        assert_eq!(key("mouseback"), (KeyCode::BTN_SIDE, false));
+        assert_eq!(key("code:183"), (KeyCode::KEY_F13, false));
+        assert_eq!(key("code:763"), (KeyCode::new(763), false));
+        for invalid in [
+            "nonsense",
+            "code:",
+            "code:abc",
+            "code:70000",
+            "code:768",
+            "code:764",
+        ] {
+            assert!(parse_key(invalid, None).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn key_names_round_trip() {
+        for key in [
+            KeyCode::KEY_A,
+            KeyCode::KEY_DOT,
+            KeyCode::KEY_VOLUMEUP,
+            KeyCode::BTN_SIDE,
+            KeyCode::new(0x2f0),
+        ] {
+            assert_eq!(parse_key(&key_name(key), None).unwrap().0, key);
+        }
+        assert_eq!(key_name(KeyCode::new(0x2f0)), "code:752");
+    }
+
+    #[test]
+    fn combo_names_are_normalised() {
+        let combo = parse_key_combo("Ctrl + KEY_VOLUMEUP", None).unwrap();
+        assert_eq!(&*combo.name, "ctrl+volumeup");
+        assert_eq!(
+            &*parse_key_combo("super+code:183", None).unwrap().name,
+            "super+f13"
+        );
+    }
+
+    #[test]
+    fn shifted_symbols_add_shift() {
+        let combo = parse_key_combo("ctrl+>", None).unwrap();
+        assert_eq!(combo.key, KeyCode::KEY_DOT);
+        assert_eq!(&*combo.name, "ctrl+shift+.");
+        assert_eq!(
+            &*parse_key_combo("ctrl+\\+", None).unwrap().name,
+            "ctrl+shift+="
+        );
+        assert_eq!(
+            &*parse_key_combo("ctrl+plus", None).unwrap().name,
+            "ctrl+shift+="
+        );
+        // Already there, not added twice
+        assert_eq!(
+            &*parse_key_combo("shift+ctrl+?", None).unwrap().name,
+            "shift+ctrl+/"
+        );
+    }
+
+    #[cfg(feature = "xkb")]
+    #[test]
+    fn digits_stay_positional_on_a_layout() {
+        let fr = Layout::new("fr").unwrap();
+        assert_eq!(parse_key("1", Some(&fr)).unwrap(), (KeyCode::KEY_1, false));
+        assert_eq!(parse_key("&", Some(&fr)).unwrap(), (KeyCode::KEY_1, false));
+        assert_eq!(parse_key("a", Some(&fr)).unwrap(), (KeyCode::KEY_Q, false));
+        // A symbol only AltGr reaches isn't taken from US QWERTY instead
+        for symbol in ["@", "{", "["] {
+            assert!(parse_key(symbol, Some(&fr)).is_err(), "{symbol}");
+        }
+        // Typing still goes through the layout
+        assert_eq!(
+            typed_keys("1", Some(&fr)).unwrap(),
+            vec![(KeyCode::KEY_1, true)]
+        );
+    }
+
+    #[test]
+    fn wheel_names_are_their_own_keys() {
+        assert_eq!(parse_key("wheelup", None).unwrap(), (WHEEL_UP, false));
+        assert_eq!(
+            &*parse_key_combo("super+wheeldown", None).unwrap().name,
+            "super+wheeldown"
+        );
+        for wheel in [WHEEL_UP, WHEEL_DOWN, WHEEL_LEFT, WHEEL_RIGHT] {
+            assert!(!key_name(wheel).starts_with("code:"));
+            assert!(parse_key(&format!("code:{}", wheel.code()), None).is_err());
+        }
+    }
+
+    #[test]
+    fn mousekeys_speeds_must_be_positive() {
+        let config = |settings: &str| {
+            parse_config(&format!(
+                "remap = []\n[mousekeys]\nlayer = \"capslock\"\n{settings}"
+            ))
+        };
+        let defaults = config("").unwrap().mousekeys.unwrap();
+        assert_eq!(defaults.start_speed, 200.0);
+        assert_eq!(defaults.max_speed, 1500.0);
+        // A faster start raises the default top speed with it
+        assert_eq!(
+            config("start_speed = 2000.0")
+                .unwrap()
+                .mousekeys
+                .unwrap()
+                .max_speed,
+            2000.0
+        );
+        for invalid in [
+            "start_speed = 0.0",
+            "max_speed = -5.0",
+            "curve = 0.0",
+            "scroll_rate = nan",
+            "start_speed = 500.0\nmax_speed = 100.0",
+        ] {
+            assert!(config(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn realtime_priority_must_be_a_sched_fifo_priority() {
+        let config = |priority| {
+            parse_config(&format!(
+                "low_latency = true\nrealtime_priority = {priority}\nremap = []"
+            ))
+        };
+        assert!(config(0).is_err());
+        assert!(config(100).is_err());
+        assert_eq!(config(1).unwrap().realtime_priority, Some(1));
+        assert_eq!(config(99).unwrap().realtime_priority, Some(99));
+
+        // The shared runtime's threads are never made realtime
+        assert!(parse_config("realtime_priority = 10\nremap = []").is_err());
+    }
+
+    #[test]
+    fn secure_input_matches_classes_exactly_and_titles_anywhere() {
+        let secure = SecureInput {
+            classes: vec!["pinentry-qt".to_string()],
+            titles: vec!["sudo".to_string()],
+        };
+        assert!(secure.matches("Pinentry-Qt", "Enter passphrase"));
+        assert!(!secure.matches("pinentry", "Enter passphrase"));
+        assert!(secure.matches("org.kde.konsole", "SUDO apt upgrade — Konsole"));
+        assert!(secure.matches("org.kde.konsole", "[sudo] password for me"));
+        assert!(!secure.matches("org.kde.konsole", "su — Konsole"));
+        assert!(!SecureInput::default().matches("pinentry-qt", "sudo"));
+    }
+
+    #[test]
+    fn hotstring_text_is_typed_on_us_keys_without_a_layout() {
+        assert_eq!(
+            typed_keys("Hi!\n", None).unwrap(),
+            vec![
+                (KeyCode::KEY_H, true),
+                (KeyCode::KEY_I, false),
+                (KeyCode::KEY_1, true),
+                (KeyCode::KEY_ENTER, false),
+            ]
+        );
+        assert!(typed_keys("café", None).is_err());
+    }
+
+    #[cfg(feature = "xkb")]
+    #[test]
+    fn hotstring_text_only_uses_keys_the_layout_has() {
+        let de = Layout::new("de").unwrap();
+        assert_eq!(
+            typed_keys("z y", Some(&de)).unwrap(),
+            vec![
+                (KeyCode::KEY_Y, false),
+                (KeyCode::KEY_SPACE, false),
+                (KeyCode::KEY_Z, false),
+            ]
+        );
+        // Only behind AltGr on a German keyboard, and not where US has it
+        let err = typed_keys("{", Some(&de)).unwrap_err();
+        assert_eq!(err.to_string(), "no key types '{' in \"{\"");
+    }
+}
@@ -0,0 +1,251 @@
+use crate::config::{self, LoadedConfig};
+use crate::metrics::Metrics;
+use crate::window::SharedActiveWindow;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::Type;
+
+pub const CONTROL_PATH: &str = "/control";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct Status {
+    pub paused: bool,
+    pub config_path: String,
+    pub rules: u32,
+    pub devices: u32,
+    pub active_window: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct RuleInfo {
+    pub from: String,
+    pub to: String,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// Things `splash-damage ctl watch` gets to see as they happen.
+#[derive(Debug, Clone)]
+pub enum Event {
+    ActiveWindow {
+        resource_class: String,
+        caption: String,
+    },
+    RuleFired {
+        from: Arc<str>,
+        to: Arc<str>,
+        resource_class: Arc<str>,
+    },
+}
+
+/// State shared between the device tasks and the control interface.
+pub struct Daemon {
+    config_path: PathBuf,
+    config: watch::Sender<Arc<LoadedConfig>>,
+    paused: watch::Sender<bool>,
+    devices: Mutex<Vec<DeviceInfo>>,
+    events: broadcast::Sender<Event>,
+    active_window: SharedActiveWindow,
+    metrics: Option<Arc<Metrics>>,
+}
+
+pub type SharedDaemon = Arc<Daemon>;
+
+impl Daemon {
+    pub fn new(
+        config_path: PathBuf,
+        config: LoadedConfig,
+        active_window: SharedActiveWindow,
+    ) -> SharedDaemon {
+        let (events, _) = broadcast::channel(256);
+        // Decided once at startup, a reload doesn't turn metrics on or off.
+        let metrics = config.metrics.then(|| {
+            let metrics = Arc::new(Metrics::default());
+            metrics.spawn_rule_counter(events.subscribe());
+            metrics
+        });
+        Arc::new(Self {
+            config_path,
+            config: watch::Sender::new(Arc::new(config)),
+            paused: watch::Sender::new(false),
+            devices: Mutex::new(Vec::new()),
+            events,
+            active_window,
+            metrics,
+        })
+    }
+
+    pub fn config(&self) -> watch::Receiver<Arc<LoadedConfig>> {
+        self.config.subscribe()
+    }
+
+    pub fn paused(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+
+    pub fn events(&self) -> broadcast::Sender<Event> {
+        self.events.clone()
+    }
+
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.clone()
+    }
+
+    pub fn add_device(&self, device: DeviceInfo) {
+        self.devices.lock().unwrap().push(device);
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+        info!(
+            paused,
+            "remapping {}",
+            if paused { "paused" } else { "resumed" }
+        );
+    }
+
+    fn reload(&self) -> anyhow::Result<usize> {
+        let loaded = config::load_config(&self.config_path)?;
+        let count = loaded.rules.len();
+        self.config.send_replace(Arc::new(loaded));
+        info!(
+            "reloaded {count} remap rules from {}",
+            self.config_path.display()
+        );
+        Ok(count)
+    }
+}
+
+struct ControlService {
+    daemon: SharedDaemon,
+}
+
+#[zbus::interface(name = "com.splashdamage.Control")]
+impl ControlService {
+    async fn status(&self) -> Status {
+        let active_window = self
+            .daemon
+            .active_window
+            .read()
+            .await
+            .as_ref()
+            .map(|w| w.resource_class.to_string())
+            .unwrap_or_default();
+        Status {
+            paused: *self.daemon.paused.borrow(),
+            config_path: self.daemon.config_path.display().to_string(),
+            rules: self.daemon.config.borrow().rules.len() as u32,
+            devices: self.daemon.devices.lock().unwrap().len() as u32,
+            active_window,
+        }
+    }
+
+    async fn pause(&self) {
+        self.daemon.set_paused(true);
+    }
+
+    async fn resume(&self) {
+        self.daemon.set_paused(false);
+    }
+
+    async fn reload(&self) -> zbus::fdo::Result<u32> {
+        self.daemon.reload().map(|n| n as u32).map_err(|e| {
+            warn!("failed to reload config: {e}");
+            zbus::fdo::Error::Failed(e.to_string())
+        })
+    }
+
+    async fn devices(&self) -> Vec<DeviceInfo> {
+        self.daemon.devices.lock().unwrap().clone()
+    }
+
+    async fn rules(&self) -> Vec<RuleInfo> {
+        self.daemon
+            .config
+            .borrow()
+            .rules
+            .iter()
+            .map(|rule| RuleInfo {
+                from: rule.from.name.to_string(),
+                to: rule.to.name.to_string(),
+                include: rule.include.clone(),
+                exclude: rule.exclude.clone(),
+            })
+            .collect()
+    }
+
+    async fn metrics(&self) -> zbus::fdo::Result<String> {
+        self.daemon
+            .metrics
+            .as_ref()
+            .map(|m| m.render())
+            .ok_or_else(|| {
+                zbus::fdo::Error::Failed("metrics are disabled, set metrics = true".to_string())
+            })
+    }
+
+    #[zbus(signal)]
+    async fn active_window_changed(
+        emitter: &SignalEmitter<'_>,
+        resource_class: &str,
+        caption: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn rule_fired(
+        emitter: &SignalEmitter<'_>,
+        from: &str,
+        to: &str,
+        resource_class: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Serve the control interface on `connection` and forward daemon events as signals.
+pub async fn serve(connection: &zbus::Connection, daemon: SharedDaemon) -> anyhow::Result<()> {
+    let mut events = daemon.events.subscribe();
+
+    connection
+        .object_server()
+        .at(CONTROL_PATH, ControlService { daemon })
+        .await?;
+
+    let emitter = SignalEmitter::new(connection, CONTROL_PATH)?.into_owned();
+    tokio::spawn(async move {
+        loop {
+            let result = match events.recv().await {
+                Ok(Event::ActiveWindow {
+                    resource_class,
+                    caption,
+                }) => {
+                    ControlService::active_window_changed(&emitter, &resource_class, &caption).await
+                }
+                Ok(Event::RuleFired {
+                    from,
+                    to,
+                    resource_class,
+                }) => ControlService::rule_fired(&emitter, &from, &to, &resource_class).await,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("control interface dropped {n} events");
+                    Ok(())
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(e) = result {
+                warn!("failed to emit control signal: {e}");
+            }
+        }
+    });
+
+    info!("serving control interface at {CONTROL_PATH}");
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use crate::control::{CONTROL_PATH, DeviceInfo, RuleInfo, Status};
+use crate::window;
+use futures_lite::StreamExt;
+use serde_json::json;
+use zbus::proxy;
+
+const USAGE: &str =
+    "usage: splash-damage ctl [--json] <status|pause|resume|reload|devices|rules|metrics|watch>";
+
+#[proxy(
+    interface = "com.splashdamage.Control",
+    default_service = "com.splashdamage.ActiveWindow",
+    default_path = "/control"
+)]
+trait Control {
+    fn status(&self) -> zbus::Result<Status>;
+    fn pause(&self) -> zbus::Result<()>;
+    fn resume(&self) -> zbus::Result<()>;
+    fn reload(&self) -> zbus::Result<u32>;
+    fn devices(&self) -> zbus::Result<Vec<DeviceInfo>>;
+    fn rules(&self) -> zbus::Result<Vec<RuleInfo>>;
+    fn metrics(&self) -> zbus::Result<String>;
+
+    #[zbus(signal)]
+    fn active_window_changed(&self, resource_class: &str, caption: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn rule_fired(&self, from: &str, to: &str, resource_class: &str) -> zbus::Result<()>;
+}
+
+/// Entry point for `splash-damage ctl ...`; `args` excludes the `ctl` itself.
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    let mut commands = args.iter().filter(|a| *a != "--json");
+    let (Some(command), None) = (commands.next(), commands.next()) else {
+        anyhow::bail!("{USAGE}");
+    };
+
+    let bus_addr = window::find_session_bus_address()?;
+    let connection = zbus::connection::Builder::address(bus_addr.as_str())?
+        .build()
+        .await?;
+    let proxy = ControlProxy::builder(&connection)
+        .path(CONTROL_PATH)?
+        .build()
+        .await?;
+
+    match command.as_str() {
+        "status" => {
+            let status = proxy.status().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                let state = if status.paused { "paused" } else { "running" };
+                println!("state:         {state}");
+                println!("config:        {}", status.config_path);
+                println!("rules:         {}", status.rules);
+                println!("devices:       {}", status.devices);
+                println!("active window: {}", status.active_window);
+            }
+        }
+        "pause" | "resume" => {
+            if command == "pause" {
+                proxy.pause().await?;
+            } else {
+                proxy.resume().await?;
+            }
+            if json {
+                println!("{}", json!({ "paused": command == "pause" }));
+            } else {
+                println!("remapping {}d", command);
+            }
+        }
+        "reload" => {
+            let rules = proxy.reload().await?;
+            if json {
+                println!("{}", json!({ "rules": rules }));
+            } else {
+                println!("reloaded {rules} remap rules");
+            }
+        }
+        "devices" => {
+            let devices = proxy.devices().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&devices)?);
+            } else {
+                for device in devices {
+                    println!("{}\t{}", device.path, device.name);
+                }
+            }
+        }
+        "rules" => {
+            let rules = proxy.rules().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&rules)?);
+            } else {
+                for rule in rules {
+                    print!("{} -> {}", rule.from, rule.to);
+                    if !rule.include.is_empty() {
+                        print!("  include: {}", rule.include.join(", "));
+                    }
+                    if !rule.exclude.is_empty() {
+                        print!("  exclude: {}", rule.exclude.join(", "));
+                    }
+                    println!();
+                }
+            }
+        }
+        "metrics" => {
+            let metrics = proxy.metrics().await?;
+            if json {
+                println!("{}", json!({ "openmetrics": metrics }));
+            } else {
+                print!("{metrics}");
+            }
+        }
+        "watch" => watch(&proxy, json).await?,
+        _ => anyhow::bail!("unknown command: {command}\n{USAGE}"),
+    }
+
+    Ok(())
+}
+
+async fn watch(proxy: &ControlProxy<'_>, json: bool) -> anyhow::Result<()> {
+    let mut windows = proxy.receive_active_window_changed().await?;
+    let mut fired = proxy.receive_rule_fired().await?;
+
+    loop {
+        tokio::select! {
+            Some(signal) = windows.next() => {
+                let args = signal.args()?;
+                if json {
+                    println!(
+                        "{}",
+                        json!({
+                            "event": "active_window",
+                            "resource_class": args.resource_class,
+                            "caption": args.caption,
+                        })
+                    );
+                } else {
+                    println!("window  {}  {:?}", args.resource_class, args.caption);
+                }
+            }
+            Some(signal) = fired.next() => {
+                let args = signal.args()?;
+                if json {
+                    println!(
+                        "{}",
+                        json!({
+                            "event": "rule_fired",
+                            "from": args.from,
+                            "to": args.to,
+                            "resource_class": args.resource_class,
+                        })
+                    );
+                } else {
+                    println!("rule    {} -> {}  ({})", args.from, args.to, args.resource_class);
+                }
+            }
+            else => break,
+        }
+    }
+
+    Ok(())
+}
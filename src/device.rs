@@ -1,9 +1,37 @@
-use evdev::{Device, EventType};
+use crate::helper::Opener;
+use evdev::raw_stream::RawDevice;
+use evdev::{EventType, KeyCode, RelativeAxisCode};
 use std::path::PathBuf;
-use tracing::info;
+use tracing::{debug, info};
 
-pub fn find_keyboards() -> Vec<(PathBuf, Device)> {
-    evdev::enumerate()
+/// Every `/dev/input/event*` node that can be opened, in path order.
+fn enumerate(opener: &Opener) -> Vec<(PathBuf, RawDevice)> {
+    let Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().as_encoded_bytes().starts_with(b"event"))
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(
+            |path| match opener.open(&path).and_then(RawDevice::from_fd) {
+                Ok(device) => Some((path, device)),
+                Err(e) => {
+                    debug!("skipping {}: {e}", path.display());
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+pub fn find_keyboards(opener: &Opener) -> Vec<(PathBuf, RawDevice)> {
+    enumerate(opener)
+        .into_iter()
         .filter(|(path, device)| {
             let name = device.name().unwrap_or("unknown");
 
@@ -11,20 +39,49 @@ pub fn find_keyboards() -> Vec<(PathBuf, Device)> {
                 return false;
             }
 
-            let is_keyboard = device.supported_events().contains(EventType::KEY)
-                && device.supported_keys().is_some_and(|keys| {
-                    keys.contains(evdev::Key::KEY_A) && keys.contains(evdev::Key::KEY_Z)
-                });
-
-            if is_keyboard {
+            if is_keyboard(device) {
                 info!("found keyboard: {name} ({})", path.display());
+                return true;
+            }
+            false
+        })
+        .collect()
+}
+
+/// Mice, trackballs and the like: anything moving a pointer that isn't also
+/// a keyboard, which `find_keyboards` already takes.
+pub fn find_pointers(opener: &Opener) -> Vec<(PathBuf, RawDevice)> {
+    enumerate(opener)
+        .into_iter()
+        .filter(|(path, device)| {
+            let name = device.name().unwrap_or("unknown");
+
+            if name.starts_with("splash-damage") || is_keyboard(device) {
+                return false;
             }
-            is_keyboard
+
+            let is_pointer = device.supported_relative_axes().is_some_and(|axes| {
+                axes.contains(RelativeAxisCode::REL_X) && axes.contains(RelativeAxisCode::REL_Y)
+            }) && device
+                .supported_keys()
+                .is_some_and(|keys| keys.contains(KeyCode::BTN_LEFT));
+
+            if is_pointer {
+                info!("found pointer: {name} ({})", path.display());
+            }
+            is_pointer
         })
         .collect()
 }
 
-pub fn grab_device(device: &mut Device) -> std::io::Result<()> {
+fn is_keyboard(device: &RawDevice) -> bool {
+    device.supported_events().contains(EventType::KEY)
+        && device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(KeyCode::KEY_A) && keys.contains(KeyCode::KEY_Z))
+}
+
+pub fn grab_device(device: &mut RawDevice) -> std::io::Result<()> {
     device.grab()?;
     info!("grabbed device: {}", device.name().unwrap_or("unknown"));
     Ok(())
@@ -0,0 +1,222 @@
+use evdev::{EventType, InputEvent, KeyCode};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+const DEFAULT_CHORD: &str = "ctrl+alt+shift+backspace";
+const DEFAULT_HOLD: Duration = Duration::from_secs(2);
+
+/// The key chord that, held long enough, makes the daemon let go of every
+/// keyboard and exit. Modifiers match either side.
+#[derive(Debug, Clone)]
+pub struct EmergencyChord {
+    pub keys: Vec<KeyCode>,
+    pub hold: Duration,
+}
+
+impl Default for EmergencyChord {
+    fn default() -> Self {
+        Self {
+            keys: crate::config::parse_chord(DEFAULT_CHORD).unwrap(),
+            hold: DEFAULT_HOLD,
+        }
+    }
+}
+
+impl EmergencyChord {
+    /// Build the chord from the `[emergency]` table. Anything invalid falls
+    /// back to the default rather than failing, so a broken config can never
+    /// take away the way out.
+    pub fn from_value(value: Option<&toml::Value>) -> Self {
+        let mut chord = Self::default();
+        let table = match value {
+            None => return chord,
+            Some(toml::Value::Table(table)) => table,
+            Some(_) => {
+                warn!("emergency must be a table, using default");
+                return chord;
+            }
+        };
+
+        match table.get("chord").map(|v| v.as_str()) {
+            None => {}
+            Some(Some(s)) => match crate::config::parse_chord(s) {
+                Ok(keys) if keys.len() >= 2 => {
+                    chord.keys = keys.into_iter().map(left_variant).collect()
+                }
+                Ok(_) => warn!("emergency chord {s:?} needs at least two keys, using default"),
+                Err(e) => warn!("invalid emergency chord {s:?}: {e}, using default"),
+            },
+            Some(None) => warn!("emergency chord must be a string, using default"),
+        }
+
+        match table.get("hold_ms").map(|v| v.as_integer()) {
+            None => {}
+            Some(Some(ms)) if ms > 0 => chord.hold = Duration::from_millis(ms as u64),
+            Some(_) => warn!("emergency hold_ms must be a positive integer, using default"),
+        }
+
+        chord
+    }
+}
+
+/// Watches raw physical events for the emergency chord.
+pub struct EmergencyDetector {
+    chord: EmergencyChord,
+    held: HashSet<KeyCode>,
+    since: Option<Instant>,
+    /// Chord keys pressed while the chord was complete, kept from the
+    /// remapper until they are released
+    swallowed: HashSet<KeyCode>,
+}
+
+impl EmergencyDetector {
+    pub fn new(chord: EmergencyChord) -> Self {
+        Self {
+            chord,
+            held: HashSet::new(),
+            since: None,
+            swallowed: HashSet::new(),
+        }
+    }
+
+    /// Track `event` and return whether it belongs to the chord and must not
+    /// reach the remapper: the press that completes the chord, any chord key
+    /// pressed again while it stays complete, and their repeats and releases.
+    /// Holding backspace for two seconds would otherwise delete text in the
+    /// focused window.
+    pub fn observe(&mut self, event: &InputEvent) -> bool {
+        if event.event_type() != EventType::KEY {
+            return false;
+        }
+
+        let key = left_variant(KeyCode::new(event.code()));
+        match event.value() {
+            1 => {
+                self.held.insert(key);
+            }
+            0 => {
+                self.held.remove(&key);
+            }
+            _ => return self.swallowed.contains(&key),
+        }
+
+        let all_held = self.chord.keys.iter().all(|k| self.held.contains(k));
+        match (all_held, self.since) {
+            (true, None) => self.since = Some(Instant::now()),
+            (false, Some(_)) => self.since = None,
+            _ => {}
+        }
+
+        match event.value() {
+            1 if self.since.is_some() && self.chord.keys.contains(&key) => {
+                self.swallowed.insert(key);
+                true
+            }
+            0 => self.swallowed.remove(&key),
+            _ => false,
+        }
+    }
+
+    /// When the chord will have been held long enough, if it is held at all.
+    fn deadline(&self) -> Option<Instant> {
+        self.since.map(|since| since + self.chord.hold)
+    }
+
+    /// Resolves once the chord has been held long enough; pending while it isn't held.
+    pub async fn held_long_enough(&self) {
+        match self.deadline() {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn triggered(&self) -> bool {
+        self.deadline().is_some_and(|d| Instant::now() >= d)
+    }
+}
+
+fn left_variant(key: KeyCode) -> KeyCode {
+    match key {
+        KeyCode::KEY_RIGHTCTRL => KeyCode::KEY_LEFTCTRL,
+        KeyCode::KEY_RIGHTSHIFT => KeyCode::KEY_LEFTSHIFT,
+        KeyCode::KEY_RIGHTALT => KeyCode::KEY_LEFTALT,
+        KeyCode::KEY_RIGHTMETA => KeyCode::KEY_LEFTMETA,
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: KeyCode, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, key.code(), value)
+    }
+
+    fn chord(s: &str) -> EmergencyChord {
+        let value: toml::Value = toml::from_str(s).unwrap();
+        EmergencyChord::from_value(value.get("emergency"))
+    }
+
+    #[test]
+    fn invalid_settings_fall_back_to_the_default() {
+        let default = EmergencyChord::default();
+        for config in [
+            "emergency = \"ctrl+alt+esc\"",
+            "emergency = 3",
+            "[emergency]\nchord = \"ctrl+nonsense\"",
+            "[emergency]\nchord = \"esc\"",
+            "[emergency]\nchord = 5",
+        ] {
+            let chord = chord(config);
+            assert_eq!(chord.keys, default.keys, "{config}");
+            assert_eq!(chord.hold, default.hold, "{config}");
+        }
+        assert_eq!(chord("[emergency]\nhold_ms = -1").hold, DEFAULT_HOLD);
+    }
+
+    #[test]
+    fn chord_and_hold_are_configurable() {
+        let chord = chord("[emergency]\nchord = \"rctrl+esc\"\nhold_ms = 500");
+        assert_eq!(chord.keys, vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_ESC]);
+        assert_eq!(chord.hold, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn triggers_once_held_long_enough_on_either_side() {
+        let mut detector = EmergencyDetector::new(EmergencyChord {
+            keys: vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_ESC],
+            hold: Duration::ZERO,
+        });
+        detector.observe(&key(KeyCode::KEY_RIGHTCTRL, 1));
+        assert!(!detector.triggered());
+        detector.observe(&key(KeyCode::KEY_ESC, 1));
+        assert!(detector.triggered());
+        detector.observe(&key(KeyCode::KEY_ESC, 0));
+        assert!(!detector.triggered());
+    }
+
+    #[test]
+    fn chord_keys_are_consumed_while_the_chord_is_complete() {
+        let mut detector = EmergencyDetector::new(EmergencyChord {
+            keys: vec![KeyCode::KEY_LEFTCTRL, KeyCode::KEY_BACKSPACE],
+            hold: Duration::from_secs(60),
+        });
+        // Modifiers pressed on their way to the chord have already gone
+        // through, so their releases have to as well.
+        assert!(!detector.observe(&key(KeyCode::KEY_LEFTCTRL, 1)));
+        assert!(detector.observe(&key(KeyCode::KEY_BACKSPACE, 1)));
+        assert!(detector.observe(&key(KeyCode::KEY_BACKSPACE, 2)));
+        assert!(!detector.observe(&key(KeyCode::KEY_A, 1)));
+        assert!(!detector.observe(&key(KeyCode::KEY_A, 0)));
+        assert!(!detector.observe(&key(KeyCode::KEY_LEFTCTRL, 0)));
+        assert!(detector.observe(&key(KeyCode::KEY_BACKSPACE, 2)));
+        assert!(detector.observe(&key(KeyCode::KEY_BACKSPACE, 0)));
+
+        // Released before the chord completed: nothing is held back.
+        assert!(!detector.observe(&key(KeyCode::KEY_BACKSPACE, 1)));
+        assert!(!detector.observe(&key(KeyCode::KEY_BACKSPACE, 0)));
+    }
+}
@@ -0,0 +1,200 @@
+//! Opening the input devices and `/dev/uinput`, either directly or through
+//! `splash-damage-helper`, which holds `cap_dac_override` so the daemon
+//! doesn't need it. See the helper for the protocol.
+
+use nix::sys::socket::{
+    AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType, recvmsg, send, socketpair,
+};
+use std::fs::OpenOptions;
+use std::io::{self, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use tracing::{info, warn};
+
+const HELPER: &str = "splash-damage-helper";
+
+pub enum Opener {
+    /// Open devices ourselves, which takes root or `CAP_DAC_OVERRIDE`
+    Direct,
+    Helper {
+        socket: OwnedFd,
+        child: Child,
+    },
+}
+
+impl Opener {
+    /// Start the helper installed next to this binary, if there is one. It
+    /// gets its capability from the file, which `PR_SET_NO_NEW_PRIVS` would
+    /// prevent, so this has to happen before `privileges::drop_capabilities`.
+    pub fn new() -> io::Result<Self> {
+        let helper = std::env::current_exe()?.with_file_name(HELPER);
+        if !helper.exists() {
+            info!("no {HELPER} installed, opening devices directly");
+            return Ok(Self::Direct);
+        }
+        let (socket, theirs) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        let child = Command::new(&helper).stdin(Stdio::from(theirs)).spawn()?;
+        info!("opening devices through {}", helper.display());
+        Ok(Self::Helper { socket, child })
+    }
+
+    /// Open `path` for reading and, where permitted, writing.
+    pub fn open(&self, path: &Path) -> io::Result<OwnedFd> {
+        match self {
+            Self::Direct => {
+                let mut options = OpenOptions::new();
+                options
+                    .read(true)
+                    .write(true)
+                    .custom_flags(libc::O_CLOEXEC | libc::O_NOCTTY);
+                let file = options
+                    .open(path)
+                    .or_else(|_| options.write(false).open(path))?;
+                Ok(file.into())
+            }
+            Self::Helper { socket, .. } => request(socket, path),
+        }
+    }
+
+    /// Stop the helper once everything is open.
+    pub fn finish(self) {
+        if let Self::Helper { socket, mut child } = self {
+            drop(socket);
+            match child.wait() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("{HELPER} {status}"),
+                Err(e) => warn!("failed to wait for {HELPER}: {e}"),
+            }
+        }
+    }
+}
+
+fn request(socket: &OwnedFd, path: &Path) -> io::Result<OwnedFd> {
+    send(
+        socket.as_raw_fd(),
+        path.as_os_str().as_bytes(),
+        MsgFlags::MSG_NOSIGNAL,
+    )?;
+
+    let mut errno = [0u8; 4];
+    let mut cmsg = nix::cmsg_space!(RawFd);
+    let (len, fds) = {
+        let mut iov = [IoSliceMut::new(&mut errno)];
+        let msg = recvmsg::<()>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // SAFETY: descriptors passed to us are ours to close.
+                fds.extend(
+                    received
+                        .iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) }),
+                );
+            }
+        }
+        (msg.bytes, fds)
+    };
+
+    if len != errno.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{HELPER} went away"),
+        ));
+    }
+    match i32::from_ne_bytes(errno) {
+        0 => fds.into_iter().next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{HELPER} sent no fd"))
+        }),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{ControlMessage, recv, sendmsg};
+    use std::fs::File;
+    use std::io::{IoSlice, Read};
+
+    /// Stands in for the helper: "/missing" fails, anything else is a pipe
+    /// with the path written into it.
+    fn fake_helper(socket: OwnedFd) {
+        let mut request = [0u8; 64];
+        loop {
+            let len = recv(socket.as_raw_fd(), &mut request, MsgFlags::empty()).unwrap();
+            if len == 0 {
+                return;
+            }
+            let (errno, file) = match &request[..len] {
+                b"/missing" => (libc::ENOENT, None),
+                path => {
+                    let (reader, writer) = nix::unistd::pipe().unwrap();
+                    nix::unistd::write(&writer, path).unwrap();
+                    (0, Some(reader))
+                }
+            };
+            let fds = file.as_ref().map(|fd| [fd.as_raw_fd()]);
+            let cmsgs: Vec<_> = fds
+                .iter()
+                .map(|fds| ControlMessage::ScmRights(fds))
+                .collect();
+            sendmsg::<()>(
+                socket.as_raw_fd(),
+                &[IoSlice::new(&i32::to_ne_bytes(errno))],
+                &cmsgs,
+                MsgFlags::empty(),
+                None,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn receives_descriptors_and_errors_from_the_helper() {
+        let (socket, theirs) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        let helper = std::thread::spawn(move || fake_helper(theirs));
+
+        let err = request(&socket, Path::new("/missing")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let fd = request(&socket, Path::new("/dev/input/event3")).unwrap();
+        let mut contents = String::new();
+        File::from(fd).read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "/dev/input/event3");
+
+        drop(socket);
+        helper.join().unwrap();
+    }
+
+    #[test]
+    fn reports_a_helper_that_went_away() {
+        let (socket, theirs) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        drop(theirs);
+        assert!(request(&socket, Path::new("/dev/uinput")).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+/// Resolves the characters in key names to the physical key that types them
+/// on a given XKB layout, so `super+z` means the Z key on a German keyboard.
+pub struct Layout {
+    #[cfg(feature = "xkb")]
+    keymap: xkb::Keymap,
+}
+
+impl Layout {
+    #[cfg(feature = "xkb")]
+    pub fn new(layout: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            keymap: xkb::Keymap::new(layout)?,
+        })
+    }
+
+    #[cfg(not(feature = "xkb"))]
+    pub fn new(layout: &str) -> anyhow::Result<Self> {
+        anyhow::bail!("layout = {layout:?} needs splash-damage built with the `xkb` feature")
+    }
+
+    /// The key producing `ch`, and whether it takes shift, if the layout has one.
+    #[cfg(feature = "xkb")]
+    pub fn key_for(&self, ch: char) -> Option<(evdev::KeyCode, bool)> {
+        let unshifted = self.keymap.find(ch, 0).map(|key| (key, false));
+        unshifted.or_else(|| self.keymap.find(ch, 1).map(|key| (key, true)))
+    }
+
+    #[cfg(not(feature = "xkb"))]
+    pub fn key_for(&self, _ch: char) -> Option<(evdev::KeyCode, bool)> {
+        None
+    }
+}
+
+/// Which key carries a symbol, looked up through libxkbcommon.
+#[cfg(feature = "xkb")]
+mod xkb {
+    use xkbcommon::xkb;
+
+    /// XKB keycodes are evdev keycodes offset by 8, a legacy of X11.
+    const EVDEV_OFFSET: u32 = 8;
+
+    pub struct Keymap {
+        keymap: xkb::Keymap,
+    }
+
+    impl Keymap {
+        /// `layout` is an XKB layout with an optional variant: `de`, `fr(bepo)`.
+        pub fn new(layout: &str) -> anyhow::Result<Self> {
+            let (name, variant) = match layout.split_once('(') {
+                Some((name, variant)) => (name, variant.trim_end_matches(')')),
+                None => (layout, ""),
+            };
+            if [name, variant].iter().any(|part| part.contains('\0')) {
+                anyhow::bail!("unknown XKB layout: {layout}");
+            }
+            let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+            let keymap = xkb::Keymap::new_from_names(
+                &context,
+                "",
+                "",
+                name,
+                variant,
+                None,
+                xkb::KEYMAP_COMPILE_NO_FLAGS,
+            )
+            .ok_or_else(|| anyhow::anyhow!("unknown XKB layout: {layout}"))?;
+            Ok(Self { keymap })
+        }
+
+        /// The key with `ch` on shift level `level` (0 plain, 1 shifted).
+        pub fn find(&self, ch: char, level: u32) -> Option<evdev::KeyCode> {
+            let wanted = xkb::utf32_to_keysym(ch as u32);
+            if wanted.raw() == 0 {
+                return None;
+            }
+            let min = self.keymap.min_keycode().raw().max(EVDEV_OFFSET);
+            let max = self.keymap.max_keycode().raw();
+            (min..=max).find_map(|keycode| {
+                self.keymap
+                    .key_get_syms_by_level(xkb::Keycode::new(keycode), 0, level)
+                    .contains(&wanted)
+                    .then(|| evdev::KeyCode::new((keycode - EVDEV_OFFSET) as u16))
+            })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "xkb"))]
+mod tests {
+    use super::*;
+    use evdev::KeyCode;
+
+    #[test]
+    fn finds_the_key_a_character_is_on() {
+        let de = Layout::new("de").unwrap();
+        assert_eq!(de.key_for('z'), Some((KeyCode::KEY_Y, false)));
+        assert_eq!(de.key_for('y'), Some((KeyCode::KEY_Z, false)));
+        assert_eq!(de.key_for('ö'), Some((KeyCode::KEY_SEMICOLON, false)));
+        assert_eq!(de.key_for('?'), Some((KeyCode::KEY_MINUS, true)));
+        // AltGr+Q, a level we don't look at
+        assert_eq!(de.key_for('@'), None);
+
+        let fr = Layout::new("fr").unwrap();
+        assert_eq!(fr.key_for('a'), Some((KeyCode::KEY_Q, false)));
+        assert_eq!(fr.key_for('1'), Some((KeyCode::KEY_1, true)));
+    }
+
+    #[test]
+    fn reads_variants() {
+        let bepo = Layout::new("fr(bepo)").unwrap();
+        assert_eq!(bepo.key_for('b'), Some((KeyCode::KEY_Q, false)));
+        assert!(Layout::new("no-such-layout").is_err());
+    }
+}
@@ -1,21 +1,60 @@
 mod config;
+mod control;
+mod ctl;
 mod device;
+mod emergency;
+mod helper;
+mod layout;
+mod metrics;
+mod mousekeys;
+mod notify;
+mod pipeline;
+mod pointer;
+mod privileges;
 mod remap;
+mod sandbox;
+mod uinput;
 mod virtual_device;
 mod window;
 
+use evdev::raw_stream::RawDevice;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
-use tracing::{error, info};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Notify, watch};
+use tracing::{error, info, warn};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Devices opened and virtual devices created while we still have the
+/// privileges to, see `privileges`.
+struct Devices {
+    keyboards: Vec<Keyboard>,
+    pointers: Vec<(PathBuf, RawDevice, virtual_device::VirtualKeyboard)>,
+}
+
+struct Keyboard {
+    path: PathBuf,
+    dev: RawDevice,
+    virt: virtual_device::VirtualKeyboard,
+    /// Pointer for mouse keys, when configured
+    mouse: Option<virtual_device::VirtualKeyboard>,
+}
+
+fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let config_path = std::env::args()
-        .nth(1)
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|a| a == "ctl") {
+        return runtime()?.block_on(ctl::run(&args[2..]));
+    }
+
+    let config_path = args
+        .get(1)
         .map(PathBuf::from)
-        .unwrap_or_else(|| default_config_path());
+        .unwrap_or_else(default_config_path);
 
     info!("loading config from {}", config_path.display());
     let loaded = config::load_config(&config_path)?;
@@ -24,60 +63,250 @@ async fn main() -> anyhow::Result<()> {
         info!("copilot_as_meta enabled");
     }
 
-    let active_window = window::shared_active_window();
+    // Everything that needs privileges happens before the runtime starts
+    // its threads, which would otherwise each keep a copy of them.
+    let opener = helper::Opener::new()?;
+    let devices = open_devices(&opener, &loaded)?;
+    opener.finish();
+    privileges::drop_capabilities(loaded.realtime_priority.is_some())?;
+    if loaded.sandbox {
+        let script_dir = window::script_dir();
+        std::fs::create_dir_all(&script_dir)?;
+        sandbox::apply(&config_path, &script_dir);
+    }
+
+    runtime()?.block_on(run(config_path, loaded, devices))
+}
 
-    let watcher = window::start_window_watcher(active_window.clone()).await?;
+fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+}
 
-    let keyboards = device::find_keyboards();
+fn open_devices(opener: &helper::Opener, config: &config::LoadedConfig) -> anyhow::Result<Devices> {
+    let keyboards = device::find_keyboards(opener);
     if keyboards.is_empty() {
         anyhow::bail!("no keyboards found — are you running as root?");
     }
 
+    let mut devices = Devices {
+        keyboards: Vec::new(),
+        pointers: Vec::new(),
+    };
+
+    for (path, dev) in keyboards {
+        let mut virt = virtual_device::create_virtual_keyboard(
+            opener,
+            &dev,
+            config.mice,
+            config.copy_device_id,
+        )?;
+        std::thread::sleep(Duration::from_millis(50));
+        virt.release_all_modifiers()?;
+        let mouse = match config.mousekeys {
+            Some(_) => Some(virtual_device::create_virtual_mouse(opener, &dev)?),
+            None => None,
+        };
+        devices.keyboards.push(Keyboard {
+            path,
+            dev,
+            virt,
+            mouse,
+        });
+    }
+
+    if config.mice {
+        for (path, dev) in device::find_pointers(opener) {
+            let virt = virtual_device::create_virtual_pointer(opener, &dev, config.copy_device_id)?;
+            devices.pointers.push((path, dev, virt));
+        }
+    }
+
+    Ok(devices)
+}
+
+async fn run(
+    config_path: PathBuf,
+    loaded: config::LoadedConfig,
+    devices: Devices,
+) -> anyhow::Result<()> {
+    let mut notifier = notify::Notifier::from_env();
+    notifier.status("waiting for KWin");
+
+    let active_window = window::shared_active_window();
+    let daemon = control::Daemon::new(config_path, loaded, active_window.clone());
+
+    let watcher =
+        window::start_window_watcher(active_window.clone(), daemon.events(), daemon.config())
+            .await?;
+    control::serve(&watcher.connection, daemon.clone()).await?;
+
+    // Fixed at startup: reloading the config never changes the way out.
+    let emergency_chord = daemon.config().borrow().emergency.clone();
+    let emergency = Arc::new(Notify::new());
+    let (shutdown, _) = watch::channel(false);
+    let (low_latency, realtime_priority, mice) = {
+        let config = daemon.config();
+        let config = config.borrow();
+        (config.low_latency, config.realtime_priority, config.mice)
+    };
+    let seat = Arc::new(pointer::Seat::default());
+    let mousekeys = daemon.config().borrow().mousekeys.clone();
+
     let mut handles = Vec::new();
 
-    for (path, mut dev) in keyboards {
-        let rules = loaded.rules.clone();
-        let copilot_as_meta = loaded.copilot_as_meta;
+    for keyboard in devices.keyboards {
+        let Keyboard {
+            path,
+            mut dev,
+            virt,
+            mouse,
+        } = keyboard;
+        let mut config = daemon.config();
+        let paused = daemon.paused();
+        let events = daemon.events();
         let aw = active_window.clone();
+        let detector = emergency::EmergencyDetector::new(emergency_chord.clone());
+        let emergency = emergency.clone();
 
-        device::grab_device(&mut dev)?;
-
-        let mut virt = virtual_device::create_virtual_keyboard(&dev)?;
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-        virtual_device::release_all_modifiers(&mut virt)?;
-
-        let handle = tokio::spawn(async move {
-            let mut remapper = remap::Remapper::new(rules, aw, copilot_as_meta);
-            let mut stream = dev.into_event_stream().unwrap();
-
-            loop {
-                match stream.next_event().await {
-                    Ok(event) => {
-                        let remapped = remapper.process_event(event);
-                        if let Err(e) = virtual_device::emit_events(&mut virt, &remapped) {
-                            error!("failed to emit events: {e}");
-                        }
-                    }
-                    Err(e) => {
-                        error!("error reading from {}: {e}", path.display());
-                        break;
-                    }
-                }
+        if let Err(e) = device::grab_device(&mut dev) {
+            error!("failed to grab {}: {e}", path.display());
+            if let Some(metrics) = daemon.metrics() {
+                metrics.grab_failure();
             }
+            continue;
+        }
+        daemon.add_device(control::DeviceInfo {
+            path: path.display().to_string(),
+            name: dev.name().unwrap_or("unknown").to_string(),
         });
 
+        // In low-latency mode the stream has to be registered with the
+        // runtime of the thread that will be reading it.
+        let runtime = if low_latency {
+            Some(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?,
+            )
+        } else {
+            None
+        };
+        let (stream, mousekeys) = {
+            let _guard = runtime.as_ref().map(|rt| rt.enter());
+            let mousekeys = match (&mousekeys, mouse) {
+                (Some(config), Some(mouse)) => {
+                    Some(mousekeys::MouseKeys::new(config.clone(), mouse))
+                }
+                _ => None,
+            };
+            (dev.into_event_stream()?, mousekeys)
+        };
+
+        let remapper = remap::Remapper::new(&config.borrow_and_update(), aw, events);
+        let task = pipeline::DeviceTask {
+            stream,
+            virt,
+            remapper,
+            detector,
+            config,
+            paused,
+            emergency,
+            shutdown: shutdown.subscribe(),
+            metrics: daemon
+                .metrics()
+                .map(|m| m.device(&path.display().to_string())),
+            seat: mice.then(|| seat.join()),
+            mousekeys,
+            heartbeat: notifier.heartbeat(),
+            path,
+            frame: pipeline::Frame::default(),
+        };
+        let handle = match runtime {
+            Some(runtime) => pipeline::spawn_dedicated(runtime, task, realtime_priority)?,
+            None => tokio::spawn(task.run()),
+        };
+
         handles.push(handle);
     }
 
-    info!("splash-damage running — press Ctrl+C to stop");
+    if handles.is_empty() {
+        anyhow::bail!("could not grab any keyboard");
+    }
+    let keyboard_count = handles.len();
 
-    signal::ctrl_c().await?;
-    info!("shutting down");
+    for (path, mut dev, virt) in devices.pointers {
+        if let Err(e) = device::grab_device(&mut dev) {
+            error!("failed to grab {}: {e}", path.display());
+            if let Some(metrics) = daemon.metrics() {
+                metrics.grab_failure();
+            }
+            continue;
+        }
+        daemon.add_device(control::DeviceInfo {
+            path: path.display().to_string(),
+            name: dev.name().unwrap_or("unknown").to_string(),
+        });
 
-    watcher.stop_script().await;
+        let task = pointer::PointerTask::new(
+            path,
+            dev.into_event_stream()?,
+            virt,
+            seat.clone(),
+            daemon.config(),
+            shutdown.subscribe(),
+            notifier.heartbeat(),
+        );
+        handles.push(tokio::spawn(task.run()));
+    }
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    info!("splash-damage running — press Ctrl+C to stop");
+    notifier.ready(&format!(
+        "{keyboard_count} keyboards, {} pointers, window backend: kwin",
+        handles.len() - keyboard_count
+    ));
+
+    loop {
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                result?;
+                info!("received Ctrl+C, shutting down");
+                break;
+            }
+            _ = sigterm.recv() => {
+                info!("received SIGTERM, shutting down");
+                break;
+            }
+            _ = sighup.recv() => {
+                info!("received SIGHUP, shutting down");
+                break;
+            }
+            _ = emergency.notified() => {
+                warn!("emergency chord triggered, releasing all keyboards and exiting");
+                break;
+            }
+            _ = notifier.watchdog_due() => notifier.watchdog(),
+        }
+    }
 
-    for handle in handles {
-        handle.abort();
+    notifier.stopping();
+    // Each device task releases its held keys and ungrabs on the way out.
+    shutdown.send_replace(true);
+    let tasks = async {
+        for handle in handles {
+            let _ = handle.await;
+        }
+    };
+    let cleanup = async { tokio::join!(tasks, watcher.stop_script()) };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, cleanup)
+        .await
+        .is_err()
+    {
+        warn!("clean shutdown took longer than {SHUTDOWN_TIMEOUT:?}, exiting anyway");
     }
 
     Ok(())
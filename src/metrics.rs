@@ -0,0 +1,279 @@
+use crate::control::Event;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+const LATENCY_BUCKETS_US: [u64; 10] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// Counters for one grabbed device. Updated lock-free from its task.
+#[derive(Default)]
+pub struct DeviceMetrics {
+    events: AtomicU64,
+    emit_errors: AtomicU64,
+    latency: Histogram,
+    input_latency: Histogram,
+}
+
+impl DeviceMetrics {
+    pub fn events(&self, count: u64) {
+        self.events.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn emit_error(&self) {
+        self.emit_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time from reading an event to having emitted its remapped result.
+    pub fn observe_latency(&self, elapsed: Duration) {
+        self.latency.observe(elapsed);
+    }
+
+    /// Time from the kernel timestamping the physical event to our emit.
+    pub fn observe_input_latency(&self, elapsed: Duration) {
+        self.input_latency.observe(elapsed);
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len()],
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        if let Some(i) = LATENCY_BUCKETS_US.iter().position(|le| us <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, device: &str) {
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS_US.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *le as f64 / 1e6;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{device=\"{device}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{device=\"{device}\",le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(out, "{name}_sum{{device=\"{device}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{device=\"{device}\"}} {count}");
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    devices: Mutex<BTreeMap<String, Arc<DeviceMetrics>>>,
+    rules_fired: Mutex<HashMap<(String, String), u64>>,
+    /// Events the rule counter fell too far behind to see, any of which may
+    /// have been a rule firing
+    rules_missed: AtomicU64,
+    grab_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn device(&self, path: &str) -> Arc<DeviceMetrics> {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn grab_failure(&self) {
+        self.grab_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count fired rules from the daemon event stream, off the input path.
+    pub fn spawn_rule_counter(self: &Arc<Self>, mut events: broadcast::Receiver<Event>) {
+        let metrics = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(Event::RuleFired {
+                        from,
+                        to,
+                        resource_class,
+                    }) => {
+                        let rule = format!("{from} -> {to}");
+                        *metrics
+                            .rules_fired
+                            .lock()
+                            .unwrap()
+                            .entry((rule, resource_class.to_string()))
+                            .or_default() += 1;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics.rules_missed.fetch_add(n, Ordering::Relaxed);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Render everything in the OpenMetrics text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let devices = self.devices.lock().unwrap();
+        out.push_str("# TYPE splash_damage_events counter\n");
+        for (path, device) in devices.iter() {
+            let n = device.events.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "splash_damage_events_total{{device=\"{}\"}} {n}",
+                escape(path)
+            );
+        }
+        out.push_str("# TYPE splash_damage_emit_errors counter\n");
+        for (path, device) in devices.iter() {
+            let n = device.emit_errors.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "splash_damage_emit_errors_total{{device=\"{}\"}} {n}",
+                escape(path)
+            );
+        }
+        let name = "splash_damage_latency_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram\n# UNIT {name} seconds");
+        for (path, device) in devices.iter() {
+            device.latency.render(&mut out, name, &escape(path));
+        }
+        let name = "splash_damage_input_latency_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram\n# UNIT {name} seconds");
+        for (path, device) in devices.iter() {
+            device.input_latency.render(&mut out, name, &escape(path));
+        }
+        drop(devices);
+
+        out.push_str("# TYPE splash_damage_rules_fired counter\n");
+        let mut rules: Vec<_> = self
+            .rules_fired
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        rules.sort();
+        for ((rule, app), n) in rules {
+            let _ = writeln!(
+                out,
+                "splash_damage_rules_fired_total{{rule=\"{}\",app=\"{}\"}} {n}",
+                escape(&rule),
+                escape(&app)
+            );
+        }
+
+        out.push_str("# TYPE splash_damage_rules_missed counter\n");
+        let _ = writeln!(
+            out,
+            "splash_damage_rules_missed_total {}",
+            self.rules_missed.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# TYPE splash_damage_grab_failures counter\n");
+        let _ = writeln!(
+            out,
+            "splash_damage_grab_failures_total {}",
+            self.grab_failures.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn renders_openmetrics_text() {
+        let metrics = Arc::new(Metrics::default());
+        let device = metrics.device("/dev/input/event3");
+        device.events(3);
+        device.emit_error();
+        device.observe_latency(Duration::from_micros(40));
+        device.observe_input_latency(Duration::from_micros(400));
+        metrics.grab_failure();
+
+        let (events, receiver) = broadcast::channel(1);
+        metrics.spawn_rule_counter(receiver);
+        let fired = || Event::RuleFired {
+            from: "super+c".into(),
+            to: "ctrl+c".into(),
+            resource_class: "say \"hi\"".into(),
+        };
+        // The second overwrites the first before the counter gets to run
+        events.send(fired()).unwrap();
+        events.send(fired()).unwrap();
+        drop(events);
+        while Arc::strong_count(&metrics) > 1 {
+            tokio::task::yield_now().await;
+        }
+
+        let rendered = metrics.render();
+        let mut expected = String::from(
+            "# TYPE splash_damage_events counter\n\
+             splash_damage_events_total{device=\"/dev/input/event3\"} 3\n\
+             # TYPE splash_damage_emit_errors counter\n\
+             splash_damage_emit_errors_total{device=\"/dev/input/event3\"} 1\n\
+             # TYPE splash_damage_latency_seconds histogram\n\
+             # UNIT splash_damage_latency_seconds seconds\n",
+        );
+        for (le, n) in LATENCY_BUCKETS_US
+            .iter()
+            .zip([0, 0, 1, 1, 1, 1, 1, 1, 1, 1])
+        {
+            let le = *le as f64 / 1e6;
+            let _ = writeln!(
+                expected,
+                "splash_damage_latency_seconds_bucket{{device=\"/dev/input/event3\",le=\"{le}\"}} {n}"
+            );
+        }
+        assert!(rendered.starts_with(&expected), "{rendered}");
+        for line in [
+            "splash_damage_latency_seconds_bucket{device=\"/dev/input/event3\",le=\"+Inf\"} 1\n\
+             splash_damage_latency_seconds_sum{device=\"/dev/input/event3\"} 0.00004\n\
+             splash_damage_latency_seconds_count{device=\"/dev/input/event3\"} 1\n",
+            "splash_damage_input_latency_seconds_bucket{device=\"/dev/input/event3\",le=\"0.00025\"} 0\n\
+             splash_damage_input_latency_seconds_bucket{device=\"/dev/input/event3\",le=\"0.0005\"} 1\n",
+            "# TYPE splash_damage_rules_fired counter\n\
+             splash_damage_rules_fired_total{rule=\"super+c -> ctrl+c\",app=\"say \\\"hi\\\"\"} 1\n\
+             # TYPE splash_damage_rules_missed counter\n\
+             splash_damage_rules_missed_total 1\n\
+             # TYPE splash_damage_grab_failures counter\n\
+             splash_damage_grab_failures_total 1\n\
+             # EOF\n",
+        ] {
+            assert!(rendered.contains(line), "{line}\nnot in\n{rendered}");
+        }
+        assert!(rendered.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::virtual_device::{HI_RES_PER_NOTCH, VirtualKeyboard};
+use evdev::{AttributeSetRef, EventType, InputEvent, KeyCode, RelativeAxisCode};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::warn;
+
+/// How often the pointer moves while a direction key is held.
+const TICK: Duration = Duration::from_millis(8);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Move {
+        x: i32,
+        y: i32,
+    },
+    Button(KeyCode),
+    Scroll {
+        axis: RelativeAxisCode,
+        hi_res: RelativeAxisCode,
+        direction: i32,
+    },
+}
+
+/// The `[mousekeys]` table: with `layer` held, keys move, click and scroll.
+#[derive(Debug, Clone)]
+pub struct MouseKeysConfig {
+    pub layer: KeyCode,
+    pub bindings: HashMap<KeyCode, Action>,
+    /// Pixels per second when a direction key is first pressed
+    pub start_speed: f64,
+    /// Pixels per second once fully accelerated
+    pub max_speed: f64,
+    /// Time from `start_speed` to `max_speed`
+    pub acceleration: Duration,
+    /// Shape of the ramp: 1 is linear, higher starts slower and ends faster
+    pub curve: f64,
+    /// Wheel notches per second while a scroll key is held
+    pub scroll_rate: f64,
+}
+
+/// Mouse keys state for one keyboard, driving its own virtual pointer.
+pub struct MouseKeys {
+    config: MouseKeysConfig,
+    pointer: VirtualKeyboard,
+    layer_held: bool,
+    /// Keys whose press we swallowed, so their release is swallowed too
+    consumed: HashSet<KeyCode>,
+    /// Held direction and scroll keys, in the order they went down
+    held: Vec<Action>,
+    /// When the pointer started moving, for acceleration
+    since: Option<Instant>,
+    /// Sub-pixel and sub-notch movement carried over between ticks
+    remainder: (f64, f64),
+    scroll_remainder: f64,
+    interval: Interval,
+}
+
+impl MouseKeys {
+    pub fn new(config: MouseKeysConfig, pointer: VirtualKeyboard) -> Self {
+        let mut interval = tokio::time::interval(TICK);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            config,
+            pointer,
+            layer_held: false,
+            consumed: HashSet::new(),
+            held: Vec::new(),
+            since: None,
+            remainder: (0.0, 0.0),
+            scroll_remainder: 0.0,
+            interval,
+        }
+    }
+
+    /// Keys currently swallowed by the layer, which the remapper never saw.
+    pub fn consumed(&self) -> &HashSet<KeyCode> {
+        &self.consumed
+    }
+
+    /// Returns true if the event belongs to the layer and must not be remapped.
+    pub fn handle_event(&mut self, event: &InputEvent) -> bool {
+        if event.event_type() != EventType::KEY {
+            return false;
+        }
+        let key = KeyCode::new(event.code());
+        let value = event.value();
+
+        if value != 1 {
+            if !self.consumed.contains(&key) {
+                return false;
+            }
+            if value == 0 {
+                self.consumed.remove(&key);
+                self.release(key);
+            }
+            return true;
+        }
+
+        if key == self.config.layer {
+            self.layer_held = true;
+        } else if let Some(action) = self.config.bindings.get(&key).filter(|_| self.layer_held) {
+            self.press(*action);
+        } else {
+            return false;
+        }
+        self.consumed.insert(key);
+        true
+    }
+
+    fn press(&mut self, action: Action) {
+        match action {
+            Action::Button(button) => self.emit(&[button_event(button, 1)]),
+            Action::Move { .. } => {
+                if !self.held.iter().any(|a| matches!(a, Action::Move { .. })) {
+                    self.since = Some(Instant::now());
+                    self.remainder = (0.0, 0.0);
+                }
+                if self.held.is_empty() {
+                    self.interval.reset_immediately();
+                }
+                self.held.push(action);
+            }
+            Action::Scroll { .. } => {
+                // One notch straight away so a tap scrolls
+                self.scroll(action, 1);
+                self.scroll_remainder = 0.0;
+                if self.held.is_empty() {
+                    self.interval.reset();
+                }
+                self.held.push(action);
+            }
+        }
+    }
+
+    fn release(&mut self, key: KeyCode) {
+        if key == self.config.layer {
+            self.layer_held = false;
+            return;
+        }
+        let Some(action) = self.config.bindings.get(&key).copied() else {
+            return;
+        };
+        match action {
+            Action::Button(button) => self.emit(&[button_event(button, 0)]),
+            _ => {
+                if let Some(i) = self.held.iter().position(|a| *a == action) {
+                    self.held.remove(i);
+                }
+                if !self.held.iter().any(|a| matches!(a, Action::Move { .. })) {
+                    self.since = None;
+                }
+            }
+        }
+    }
+
+    /// Resolves each time the pointer is due to move; pending while nothing is held.
+    pub async fn tick_due(mousekeys: &mut Option<Self>) {
+        match mousekeys {
+            Some(mousekeys) if !mousekeys.held.is_empty() => {
+                mousekeys.interval.tick().await;
+            }
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Move and scroll for one tick's worth of the held keys.
+    pub fn tick(&mut self) {
+        let dt = TICK.as_secs_f64();
+
+        if let Some(since) = self.since {
+            let (x, y) = direction(&self.held);
+            let distance = self.speed(since.elapsed()) * dt;
+            self.remainder.0 += x * distance;
+            self.remainder.1 += y * distance;
+            let (dx, dy) = (self.remainder.0.trunc(), self.remainder.1.trunc());
+            self.remainder.0 -= dx;
+            self.remainder.1 -= dy;
+
+            let mut events = Vec::with_capacity(2);
+            if dx != 0.0 {
+                events.push(rel_event(RelativeAxisCode::REL_X, dx as i32));
+            }
+            if dy != 0.0 {
+                events.push(rel_event(RelativeAxisCode::REL_Y, dy as i32));
+            }
+            self.emit(&events);
+        }
+
+        if let Some(scroll) = self
+            .held
+            .iter()
+            .rev()
+            .find(|a| matches!(a, Action::Scroll { .. }))
+        {
+            let scroll = *scroll;
+            self.scroll_remainder += self.config.scroll_rate * dt;
+            let notches = self.scroll_remainder.trunc();
+            self.scroll_remainder -= notches;
+            if notches > 0.0 {
+                self.scroll(scroll, notches as i32);
+            }
+        }
+    }
+
+    fn speed(&self, elapsed: Duration) -> f64 {
+        let config = &self.config;
+        let ramp = if config.acceleration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / config.acceleration.as_secs_f64()).min(1.0)
+        };
+        config.start_speed + (config.max_speed - config.start_speed) * ramp.powf(config.curve)
+    }
+
+    fn scroll(&mut self, action: Action, notches: i32) {
+        if let Action::Scroll {
+            axis,
+            hi_res,
+            direction,
+        } = action
+        {
+            self.emit(&[
+                rel_event(axis, direction * notches),
+                rel_event(hi_res, direction * notches * HI_RES_PER_NOTCH),
+            ]);
+        }
+    }
+
+    /// Release whatever we missed the physical release of.
+    pub fn resync(&mut self, physical: &AttributeSetRef<KeyCode>) {
+        let released: Vec<KeyCode> = self
+            .consumed
+            .iter()
+            .filter(|key| !physical.contains(**key))
+            .copied()
+            .collect();
+        for key in released {
+            self.consumed.remove(&key);
+            self.release(key);
+        }
+    }
+
+    /// Let go of everything: buttons, movement and the layer itself.
+    pub fn reset(&mut self) {
+        if let Err(e) = self.pointer.release_all() {
+            warn!("failed to release mouse keys buttons: {e}");
+        }
+        self.layer_held = false;
+        self.consumed.clear();
+        self.held.clear();
+        self.since = None;
+    }
+
+    fn emit(&mut self, events: &[InputEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        if let Err(e) = self.pointer.emit(events) {
+            warn!("failed to emit mouse keys events: {e}");
+        }
+    }
+}
+
+/// The unit vector the held direction keys point along, so a diagonal
+/// moves no faster than a straight line.
+fn direction(held: &[Action]) -> (f64, f64) {
+    let (mut x, mut y) = (0, 0);
+    for action in held {
+        if let Action::Move { x: dx, y: dy } = action {
+            x += dx;
+            y += dy;
+        }
+    }
+    let (x, y) = (x.signum() as f64, y.signum() as f64);
+    let length = x.hypot(y);
+    if length == 0.0 {
+        (0.0, 0.0)
+    } else {
+        (x / length, y / length)
+    }
+}
+
+fn button_event(button: KeyCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, button.code(), value)
+}
+
+fn rel_event(axis: RelativeAxisCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE.0, axis.0, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UP: Action = Action::Move { x: 0, y: -1 };
+    const LEFT: Action = Action::Move { x: -1, y: 0 };
+    const RIGHT: Action = Action::Move { x: 1, y: 0 };
+
+    #[test]
+    fn diagonals_move_at_the_same_speed() {
+        assert_eq!(direction(&[LEFT]), (-1.0, 0.0));
+        let (x, y) = direction(&[UP, RIGHT]);
+        assert!((x.hypot(y) - 1.0).abs() < 1e-9);
+        assert!(x > 0.0 && y < 0.0);
+        // Opposite keys cancel out
+        assert_eq!(direction(&[LEFT, RIGHT]), (0.0, 0.0));
+        assert_eq!(direction(&[LEFT, LEFT]), (-1.0, 0.0));
+    }
+}
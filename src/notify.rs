@@ -0,0 +1,240 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time::Interval;
+use tracing::{info, warn};
+
+/// Speaks the systemd `sd_notify` protocol: plain datagrams to `$NOTIFY_SOCKET`.
+/// Everything is a no-op when not started by systemd with `Type=notify`.
+pub struct Notifier {
+    target: Option<(UnixDatagram, SocketAddr)>,
+    watchdog: Option<(Interval, Duration)>,
+    epoch: Instant,
+    heartbeats: Vec<Arc<AtomicU64>>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self::from_vars(
+            var("NOTIFY_SOCKET").as_deref(),
+            var("WATCHDOG_USEC").as_deref(),
+            var("WATCHDOG_PID").as_deref(),
+        )
+    }
+
+    /// As `from_env`, with the values of `$NOTIFY_SOCKET`, `$WATCHDOG_USEC`
+    /// and `$WATCHDOG_PID` passed in.
+    fn from_vars(
+        notify_socket: Option<&str>,
+        watchdog_usec: Option<&str>,
+        watchdog_pid: Option<&str>,
+    ) -> Self {
+        let target = notify_socket.and_then(|path| match connect(path) {
+            Ok(target) => Some(target),
+            Err(e) => {
+                warn!("ignoring NOTIFY_SOCKET={path}: {e}");
+                None
+            }
+        });
+
+        let watchdog = target
+            .as_ref()
+            .and(watchdog_interval(watchdog_usec, watchdog_pid))
+            .map(|interval| {
+                info!("systemd watchdog enabled, petting every {interval:?}");
+                (tokio::time::interval(interval), interval)
+            });
+
+        Self {
+            target,
+            watchdog,
+            epoch: Instant::now(),
+            heartbeats: Vec::new(),
+        }
+    }
+
+    /// A heartbeat for one task the watchdog should vouch for.
+    pub fn heartbeat(&mut self) -> Heartbeat {
+        let last = Arc::new(AtomicU64::new(millis_since(self.epoch)));
+        self.heartbeats.push(last.clone());
+        Heartbeat {
+            epoch: self.epoch,
+            last,
+            period: self.watchdog.as_ref().map(|(_, interval)| *interval / 2),
+            interval: None,
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// Pet the watchdog, but only if every task has beaten since the last
+    /// time. A task that has ended, or is stuck somewhere in its loop, means
+    /// a device we no longer serve; let systemd restart us rather than limp
+    /// along.
+    pub fn watchdog(&self) {
+        let Some((_, interval)) = &self.watchdog else {
+            return;
+        };
+        let now = millis_since(self.epoch);
+        let max_age = interval.as_millis() as u64;
+        let alive = self.heartbeats.iter().all(|last| {
+            Arc::strong_count(last) > 1
+                && now.saturating_sub(last.load(Ordering::Relaxed)) <= max_age
+        });
+        if alive {
+            self.send("WATCHDOG=1");
+        } else {
+            warn!("a device task stopped responding, not petting the watchdog");
+        }
+    }
+
+    /// Resolves each time the watchdog is due; pending forever if it is disabled.
+    pub async fn watchdog_due(&mut self) {
+        match &mut self.watchdog {
+            Some((interval, _)) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    fn send(&self, message: &str) {
+        if let Some((socket, addr)) = &self.target
+            && let Err(e) = socket.send_to_addr(message.as_bytes(), addr)
+        {
+            warn!("failed to notify systemd: {e}");
+        }
+    }
+}
+
+/// Proof that a task is still getting round its loop. It beats from one of
+/// the loop's own branches, so a task stuck processing an event or blocked
+/// on a write stops beating even though it hasn't ended.
+pub struct Heartbeat {
+    epoch: Instant,
+    last: Arc<AtomicU64>,
+    period: Option<Duration>,
+    /// Created on first use, under the runtime the task runs on
+    interval: Option<Interval>,
+}
+
+impl Heartbeat {
+    /// Resolves each time the task has beaten; pending forever without a watchdog.
+    pub async fn beat(&mut self) {
+        let Some(period) = self.period else {
+            return std::future::pending().await;
+        };
+        self.interval
+            .get_or_insert_with(|| tokio::time::interval(period))
+            .tick()
+            .await;
+        self.last.store(millis_since(self.epoch), Ordering::Relaxed);
+    }
+}
+
+fn millis_since(epoch: Instant) -> u64 {
+    epoch.elapsed().as_millis() as u64
+}
+
+fn connect(path: &str) -> std::io::Result<(UnixDatagram, SocketAddr)> {
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    Ok((UnixDatagram::unbound()?, addr))
+}
+
+/// Half of `$WATCHDOG_USEC`, if the watchdog is meant for this process.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv(socket: &UnixDatagram) -> Option<String> {
+        let mut buf = [0; 256];
+        match socket.recv(&mut buf) {
+            Ok(n) => Some(String::from_utf8_lossy(&buf[..n]).into_owned()),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => None,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn notifies_ready_and_pets_the_watchdog_only_while_tasks_beat() {
+        let path =
+            std::env::temp_dir().join(format!("splash-damage-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut notifier = Notifier::from_vars(
+            path.to_str(),
+            Some("200000"),
+            Some(&std::process::id().to_string()),
+        );
+        let mut heartbeat = notifier.heartbeat();
+        let gone = notifier.heartbeat();
+        notifier.ready("1 keyboards, window backend: kwin");
+        assert_eq!(
+            recv(&socket).as_deref(),
+            Some("READY=1\nSTATUS=1 keyboards, window backend: kwin")
+        );
+
+        heartbeat.beat().await;
+        notifier.watchdog();
+        assert_eq!(recv(&socket).as_deref(), Some("WATCHDOG=1"));
+
+        // A task that has ended no longer vouches for anything
+        drop(gone);
+        notifier.watchdog();
+        assert_eq!(recv(&socket), None);
+
+        notifier.heartbeats.pop();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        notifier.watchdog();
+        assert_eq!(recv(&socket), None, "a task that stopped beating");
+
+        heartbeat.beat().await;
+        notifier.watchdog();
+        assert_eq!(recv(&socket).as_deref(), Some("WATCHDOG=1"));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn the_watchdog_is_only_for_its_own_process() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("200000"), Some(&pid)),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            watchdog_interval(Some("200000"), None),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(watchdog_interval(Some("200000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
@@ -0,0 +1,520 @@
+use crate::config::LoadedConfig;
+use crate::emergency::EmergencyDetector;
+use crate::metrics::DeviceMetrics;
+use crate::mousekeys::MouseKeys;
+use crate::notify::Heartbeat;
+use crate::pointer::SeatMember;
+use crate::remap::Remapper;
+use crate::virtual_device::VirtualKeyboard;
+use evdev::raw_stream::EventStream;
+use evdev::{AttributeSet, EventSummary, EventType, InputEvent, KeyCode, SynchronizationCode};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Notify, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Interval, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+const CONSISTENCY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often queued frames are emitted, and how many at a time: a few keys
+/// every few milliseconds stays well within what the compositor buffers.
+const BACKLOG_TICK: Duration = Duration::from_millis(4);
+const BACKLOG_FRAMES_PER_TICK: usize = 4;
+
+/// Everything one grabbed keyboard needs to run its read → remap → emit loop.
+pub struct DeviceTask {
+    pub path: PathBuf,
+    pub stream: EventStream,
+    pub virt: VirtualKeyboard,
+    pub remapper: Remapper,
+    pub detector: EmergencyDetector,
+    pub config: watch::Receiver<Arc<LoadedConfig>>,
+    pub paused: watch::Receiver<bool>,
+    pub emergency: Arc<Notify>,
+    pub shutdown: watch::Receiver<bool>,
+    pub metrics: Option<Arc<DeviceMetrics>>,
+    /// Set when pointers are grabbed too, see `pointer::Seat`
+    pub seat: Option<SeatMember>,
+    pub mousekeys: Option<MouseKeys>,
+    pub heartbeat: Heartbeat,
+    pub frame: Frame,
+}
+
+/// The evdev frame being collected, plus scratch space for its remapped
+/// output. Both are reused so the read loop doesn't allocate.
+#[derive(Default)]
+pub struct Frame {
+    events: Vec<InputEvent>,
+    out: Vec<InputEvent>,
+    /// Set by SYN_DROPPED: everything up to the next SYN_REPORT is discarded.
+    dropped: bool,
+    backlog: Backlog,
+}
+
+/// Output frames waiting to be emitted a few at a time, such as a typed
+/// hotstring expansion, which written all at once would overrun the
+/// compositor's buffer. While any wait, later output queues behind them so
+/// keys stay in order.
+#[derive(Default)]
+struct Backlog {
+    frames: VecDeque<Vec<InputEvent>>,
+    interval: Option<Interval>,
+}
+
+impl Backlog {
+    /// Queue `events`, split into frames at each SYN_REPORT.
+    fn push(&mut self, events: &[InputEvent]) {
+        let frames = events
+            .split(is_syn_report)
+            .filter(|frame| !frame.is_empty());
+        self.frames.extend(frames.map(<[InputEvent]>::to_vec));
+    }
+
+    /// Resolves each time more frames are due; pending while none wait.
+    async fn due(&mut self) {
+        if self.frames.is_empty() {
+            return std::future::pending().await;
+        }
+        self.interval
+            .get_or_insert_with(|| {
+                let mut interval = tokio::time::interval(BACKLOG_TICK);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval
+            })
+            .tick()
+            .await;
+    }
+}
+
+/// Keys seen down by the previous consistency check. A key only counts as
+/// stuck once two checks in a row found it up, so an event that is already
+/// queued but not yet read doesn't look like drift.
+type PreviousCheck = Option<AttributeSet<KeyCode>>;
+
+impl DeviceTask {
+    pub async fn run(mut self) {
+        let mut check = tokio::time::interval(CONSISTENCY_CHECK_INTERVAL);
+        let mut previous: PreviousCheck = None;
+
+        loop {
+            tokio::select! {
+                result = self.stream.next_event() => match result {
+                    Ok(event) => {
+                        let chord = self.detector.observe(&event);
+                        if self.detector.triggered() {
+                            break;
+                        }
+                        if !chord {
+                            self.handle_event(event);
+                        }
+                    }
+                    Err(e) => {
+                        error!("error reading from {}: {e}", self.path.display());
+                        break;
+                    }
+                },
+                Ok(()) = self.config.changed() => {
+                    let config = self.config.borrow_and_update().clone();
+                    self.remapper.set_config(&config);
+                    self.reset();
+                }
+                Ok(()) = self.paused.changed() => {
+                    self.paused.borrow_and_update();
+                    self.reset();
+                }
+                result = self.virt.next_feedback() => match result {
+                    Ok(event) => self.forward_feedback(event),
+                    Err(e) => warn!(
+                        "no longer forwarding LEDs to {}: {e}",
+                        self.path.display()
+                    ),
+                },
+                frame = SeatMember::next_frame(&mut self.seat) => self.process_handed(frame),
+                _ = self.frame.backlog.due() => self.emit_backlog(),
+                _ = MouseKeys::tick_due(&mut self.mousekeys) => {
+                    if let Some(mousekeys) = &mut self.mousekeys {
+                        mousekeys.tick();
+                    }
+                }
+                _ = check.tick() => self.check_consistency(&mut previous),
+                _ = self.heartbeat.beat() => {}
+                _ = self.detector.held_long_enough() => break,
+                Ok(()) = self.shutdown.changed() => break,
+            }
+        }
+
+        if self.detector.triggered() {
+            error!("emergency chord held, releasing {}", self.path.display());
+            self.emergency.notify_one();
+        }
+
+        if let Err(e) = self.virt.release_all() {
+            warn!(
+                "failed to release held keys for {}: {e}",
+                self.path.display()
+            );
+        }
+        if let Err(e) = self.stream.device_mut().ungrab() {
+            warn!("failed to ungrab {}: {e}", self.path.display());
+        }
+    }
+
+    /// Collect events up to SYN_REPORT so each physical frame is remapped as a
+    /// unit and emitted as one frame.
+    fn handle_event(&mut self, event: InputEvent) {
+        match event.destructure() {
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_DROPPED, _) => {
+                warn!("{} dropped events, resynchronising", self.path.display());
+                self.frame.events.clear();
+                self.frame.dropped = true;
+            }
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => {
+                if std::mem::take(&mut self.frame.dropped) {
+                    self.resync();
+                } else {
+                    self.process_frame(event.timestamp());
+                }
+            }
+            _ if self.frame.dropped => {}
+            _ => self.frame.events.push(event),
+        }
+    }
+
+    fn process_frame(&mut self, timestamp: SystemTime) {
+        let start = Instant::now();
+        let secure = self.remapper.refresh_secure();
+        if let Some(metrics) = &self.metrics
+            && !secure
+        {
+            // The SYN_REPORT ending the frame counts too
+            metrics.events(self.frame.events.len() as u64 + 1);
+        }
+
+        if let Some(seat) = &self.seat
+            && seat.take_click()
+        {
+            self.remapper.forget_typed();
+        }
+
+        let mut out = std::mem::take(&mut self.frame.out);
+        out.clear();
+        if *self.paused.borrow() {
+            out.extend_from_slice(&self.frame.events);
+        } else {
+            for event in &self.frame.events {
+                // In a secure-input window the layer only gets the keys it
+                // already has down, so letting go of them stops the pointer
+                if let Some(mousekeys) = &mut self.mousekeys
+                    && (!secure
+                        || event.event_type() == EventType::KEY
+                            && mousekeys.consumed().contains(&KeyCode::new(event.code())))
+                    && mousekeys.handle_event(event)
+                {
+                    // Moving or clicking the pointer can move the caret
+                    if event.value() == 1 {
+                        self.remapper.forget_typed();
+                    }
+                    continue;
+                }
+                self.remapper.process_event(*event, &mut out);
+            }
+        }
+        self.frame.events.clear();
+        self.emit(&out);
+        self.frame.out = out;
+
+        if let Some(metrics) = &self.metrics
+            && !secure
+        {
+            metrics.observe_latency(start.elapsed());
+            if let Ok(since_kernel) = SystemTime::now().duration_since(timestamp) {
+                metrics.observe_input_latency(since_kernel);
+            }
+        }
+    }
+
+    /// Remap buttons and wheel notches a pointer handed over, as if they had
+    /// come from this keyboard.
+    fn process_handed(&mut self, events: Vec<InputEvent>) {
+        self.remapper.refresh_secure();
+        let mut out = std::mem::take(&mut self.frame.out);
+        out.clear();
+        if *self.paused.borrow() {
+            out.extend_from_slice(&events);
+        } else {
+            for event in events {
+                self.remapper.process_event(event, &mut out);
+            }
+        }
+        self.emit(&out);
+        self.frame.out = out;
+    }
+
+    /// Pass LED and sound writes made to the virtual device on to the
+    /// physical one, so the CapsLock light follows CapsLock again.
+    fn forward_feedback(&mut self, event: InputEvent) {
+        if !matches!(event.event_type(), EventType::LED | EventType::SOUND) {
+            return;
+        }
+        if let Err(e) = self.stream.device_mut().send_events(&[event]) {
+            warn!(
+                "failed to forward {event:?} to {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+
+    /// After SYN_DROPPED the events we saw no longer add up, so take the key
+    /// state straight from the device and emit whatever presses and releases
+    /// we missed.
+    fn resync(&mut self) {
+        let Some(physical) = self.physical_keys() else {
+            return;
+        };
+        self.remapper.refresh_secure();
+        let mut corrections = Vec::new();
+        if *self.paused.borrow() {
+            for key in self.virt.keys_down().clone() {
+                if !physical.contains(key) {
+                    corrections.push(InputEvent::new(EventType::KEY.0, key.code(), 0));
+                }
+            }
+            for key in physical.iter() {
+                if !self.virt.keys_down().contains(&key) {
+                    corrections.push(InputEvent::new(EventType::KEY.0, key.code(), 1));
+                }
+            }
+        } else {
+            let mut physical = physical;
+            if let Some(mousekeys) = &mut self.mousekeys {
+                mousekeys.resync(&physical);
+                for key in mousekeys.consumed() {
+                    physical.remove(*key);
+                }
+            }
+            self.remapper.resync(&physical, &mut corrections);
+        }
+        if !corrections.is_empty() {
+            info!(
+                "correcting {} key events after SYN_DROPPED",
+                corrections.len()
+            );
+        }
+        self.emit(&corrections);
+    }
+
+    /// Emit `events`, which are one frame unless SYN_REPORTs split them
+    /// into several; the virtual device appends the last SYN_REPORT. Several
+    /// frames, and anything emitted while earlier ones still wait, go through
+    /// the backlog.
+    fn emit(&mut self, events: &[InputEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        let backlog = &mut self.frame.backlog;
+        if !backlog.frames.is_empty() || events.iter().any(is_syn_report) {
+            backlog.push(events);
+            return;
+        }
+        self.emit_frame(events);
+    }
+
+    fn emit_backlog(&mut self) {
+        for _ in 0..BACKLOG_FRAMES_PER_TICK {
+            let Some(frame) = self.frame.backlog.frames.pop_front() else {
+                break;
+            };
+            self.emit_frame(&frame);
+        }
+    }
+
+    /// Emit `events` as a single frame; the virtual device appends the SYN_REPORT.
+    fn emit_frame(&mut self, events: &[InputEvent]) {
+        if let Err(e) = self.virt.emit(events) {
+            error!("failed to emit events: {e}");
+            if let Some(metrics) = &self.metrics {
+                metrics.emit_error();
+            }
+            // We no longer know what the virtual device has down, so start
+            // over from a clean slate on both sides.
+            let _ = self.virt.release_all();
+            let _ = self.virt.release_all_modifiers();
+            self.reset();
+        }
+        if let Some(seat) = &self.seat {
+            seat.set_modifiers_held(self.remapper.modifiers_held());
+        }
+    }
+
+    /// Release everything the virtual device holds and resynchronise the
+    /// remapper with the physical key state.
+    fn reset(&mut self) {
+        self.frame.backlog.frames.clear();
+        if let Err(e) = self.virt.release_all() {
+            warn!("failed to release held keys: {e}");
+        }
+        if let Some(mousekeys) = &mut self.mousekeys {
+            mousekeys.reset();
+        }
+        let physical = self.physical_keys().unwrap_or_default();
+        self.remapper.reset(&physical);
+    }
+
+    /// Keys down on the device, plus any buttons a pointer handed over and
+    /// still has down.
+    fn physical_keys(&self) -> Option<AttributeSet<KeyCode>> {
+        let mut keys = self
+            .stream
+            .device()
+            .get_key_state()
+            .inspect_err(|e| warn!("failed to read key state from {}: {e}", self.path.display()))
+            .ok()?;
+        if let Some(seat) = &self.seat {
+            for key in seat.held() {
+                keys.insert(*key);
+            }
+        }
+        Some(keys)
+    }
+
+    /// Compare what we believe is held with EVIOCGKEY and correct any drift.
+    fn check_consistency(&mut self, previous: &mut PreviousCheck) {
+        let Some(current) = self.physical_keys() else {
+            return;
+        };
+        let Some(mut physical) = previous.replace(current.clone()) else {
+            return;
+        };
+        for key in current.iter() {
+            physical.insert(key);
+        }
+        self.remapper.refresh_secure();
+
+        if !*self.paused.borrow() {
+            let mut stale = Vec::new();
+            self.remapper.release_stale(&physical, &mut stale);
+            self.emit(&stale);
+        }
+
+        // Keys being typed from the backlog aren't held on the device
+        if physical.iter().next().is_none()
+            && !self.virt.keys_down().is_empty()
+            && self.frame.backlog.frames.is_empty()
+        {
+            if self.remapper.secure() {
+                warn!(
+                    "no keys held on {} but virtual keys are down",
+                    self.path.display()
+                );
+            } else {
+                warn!(
+                    "no keys held on {} but virtual keys {:?} are down",
+                    self.path.display(),
+                    self.virt.keys_down()
+                );
+            }
+            if let Err(e) = self.virt.release_all() {
+                warn!("failed to release held keys: {e}");
+            }
+        }
+    }
+}
+
+fn is_syn_report(event: &InputEvent) -> bool {
+    event.event_type() == EventType::SYNCHRONIZATION
+        && event.code() == SynchronizationCode::SYN_REPORT.0
+}
+
+/// Run a device task on its own thread and current-thread runtime, away from
+/// everything else the daemon does. `runtime` must be the one the task's event
+/// stream was created under. The returned handle finishes when the thread's
+/// task does; the thread itself is never waited for, so it can't hold up
+/// shutdown past its timeout.
+pub fn spawn_dedicated(
+    runtime: tokio::runtime::Runtime,
+    task: DeviceTask,
+    realtime_priority: Option<i32>,
+) -> std::io::Result<JoinHandle<()>> {
+    let name = task
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "device".to_string());
+    let (done, finished) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new().name(name).spawn(move || {
+        if let Some(priority) = realtime_priority {
+            set_realtime_priority(&task.path, priority);
+        }
+        runtime.block_on(task.run());
+        let _ = done.send(());
+    })?;
+    // Also resolves if the thread panicked and dropped `done`
+    Ok(tokio::spawn(async move {
+        let _ = finished.await;
+    }))
+}
+
+fn set_realtime_priority(path: &std::path::Path, priority: i32) {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: pid 0 means the calling thread and `param` outlives the call.
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } == 0 {
+        info!(
+            "running {} with SCHED_FIFO priority {priority}",
+            path.display()
+        );
+    } else {
+        warn!(
+            "could not set SCHED_FIFO priority {priority} for {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_queues_one_frame_per_syn_report() {
+        let key = |key: KeyCode, value| InputEvent::new(EventType::KEY.0, key.code(), value);
+        let syn_report = InputEvent::new(
+            EventType::SYNCHRONIZATION.0,
+            SynchronizationCode::SYN_REPORT.0,
+            0,
+        );
+        let mut backlog = Backlog::default();
+        backlog.push(&[
+            key(KeyCode::KEY_A, 1),
+            syn_report,
+            key(KeyCode::KEY_A, 0),
+            syn_report,
+            key(KeyCode::KEY_LEFTSHIFT, 0),
+            key(KeyCode::KEY_B, 1),
+        ]);
+        // Output that arrives while frames wait lines up behind them
+        backlog.push(&[key(KeyCode::KEY_B, 0)]);
+
+        let frames: Vec<Vec<(u16, i32)>> = backlog
+            .frames
+            .iter()
+            .map(|frame| frame.iter().map(|e| (e.code(), e.value())).collect())
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                vec![(KeyCode::KEY_A.code(), 1)],
+                vec![(KeyCode::KEY_A.code(), 0)],
+                vec![
+                    (KeyCode::KEY_LEFTSHIFT.code(), 0),
+                    (KeyCode::KEY_B.code(), 1)
+                ],
+                vec![(KeyCode::KEY_B.code(), 0)],
+            ]
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use crate::config::{LoadedConfig, WHEEL_DOWN, WHEEL_LEFT, WHEEL_RIGHT, WHEEL_UP};
+use crate::notify::Heartbeat;
+use crate::virtual_device::VirtualKeyboard;
+use evdev::raw_stream::EventStream;
+use evdev::{EventSummary, EventType, InputEvent, KeyCode, RelativeAxisCode, SynchronizationCode};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+
+/// The keyboards grabbed pointers hand their remappable buttons and wheel
+/// notches to. Remapping them in a keyboard task means they see the
+/// modifiers held there, and whatever the rule emits comes out of the same
+/// virtual device as those modifiers, in order.
+#[derive(Default)]
+pub struct Seat {
+    keyboards: Mutex<Vec<SeatKeyboard>>,
+}
+
+struct SeatKeyboard {
+    frames: mpsc::UnboundedSender<Vec<InputEvent>>,
+    modifiers_held: Arc<AtomicBool>,
+    clicked: Arc<AtomicBool>,
+}
+
+/// A keyboard task's end of the seat.
+pub struct SeatMember {
+    frames: mpsc::UnboundedReceiver<Vec<InputEvent>>,
+    modifiers_held: Arc<AtomicBool>,
+    /// Set when a button is pressed on any pointer
+    clicked: Arc<AtomicBool>,
+    /// Buttons handed to us that are still down on their pointer
+    held: HashSet<KeyCode>,
+}
+
+impl Seat {
+    pub fn join(&self) -> SeatMember {
+        let (tx, frames) = mpsc::unbounded_channel();
+        let modifiers_held = Arc::new(AtomicBool::new(false));
+        let clicked = Arc::new(AtomicBool::new(false));
+        self.keyboards.lock().unwrap().push(SeatKeyboard {
+            frames: tx,
+            modifiers_held: modifiers_held.clone(),
+            clicked: clicked.clone(),
+        });
+        SeatMember {
+            frames,
+            modifiers_held,
+            clicked,
+            held: HashSet::new(),
+        }
+    }
+
+    /// Tell every keyboard a button was pressed, which may have moved the
+    /// caret out from under a half-typed hotstring.
+    fn click(&self) {
+        for keyboard in self.keyboards.lock().unwrap().iter() {
+            keyboard.clicked.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The keyboard to remap through: the one a modifier is held on, so
+    /// `super+wheelup` works from whichever keyboard super is on.
+    fn route(&self) -> Option<usize> {
+        let keyboards = self.keyboards.lock().unwrap();
+        let live = || {
+            keyboards
+                .iter()
+                .enumerate()
+                .filter(|(_, k)| !k.frames.is_closed())
+        };
+        live()
+            .find(|(_, k)| k.modifiers_held.load(Ordering::Relaxed))
+            .or_else(|| live().next())
+            .map(|(index, _)| index)
+    }
+
+    /// Hands the frame back if that keyboard's task has gone away.
+    fn send(&self, keyboard: usize, frame: Vec<InputEvent>) -> Result<(), Vec<InputEvent>> {
+        self.keyboards.lock().unwrap()[keyboard]
+            .frames
+            .send(frame)
+            .map_err(|e| e.0)
+    }
+}
+
+impl SeatMember {
+    /// The next frame handed over by a pointer; pending forever without a seat.
+    pub async fn next_frame(member: &mut Option<Self>) -> Vec<InputEvent> {
+        if let Some(member) = member
+            && let Some(frame) = member.frames.recv().await
+        {
+            for event in &frame {
+                if let EventSummary::Key(_, key, _) = event.destructure() {
+                    match event.value() {
+                        0 => {
+                            member.held.remove(&key);
+                        }
+                        1 => {
+                            member.held.insert(key);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            return frame;
+        }
+        std::future::pending().await
+    }
+
+    pub fn held(&self) -> &HashSet<KeyCode> {
+        &self.held
+    }
+
+    pub fn set_modifiers_held(&self, held: bool) {
+        self.modifiers_held.store(held, Ordering::Relaxed);
+    }
+
+    /// Whether a pointer button was pressed since the last call.
+    pub fn take_click(&self) -> bool {
+        self.clicked.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Reads one grabbed pointer. Motion and anything no rule mentions goes
+/// straight out of its own virtual device; rule triggers go to the seat.
+pub struct PointerTask {
+    path: PathBuf,
+    stream: EventStream,
+    virt: VirtualKeyboard,
+    seat: Arc<Seat>,
+    config: watch::Receiver<Arc<LoadedConfig>>,
+    shutdown: watch::Receiver<bool>,
+    heartbeat: Heartbeat,
+    /// Every key some rule is triggered by
+    triggers: HashSet<KeyCode>,
+    /// Keyboard each handed-over button went to when pressed
+    routes: HashMap<KeyCode, usize>,
+    frame: Vec<InputEvent>,
+    /// Scratch space for the part of a frame that is emitted here
+    out: Vec<InputEvent>,
+    dropped: bool,
+}
+
+impl PointerTask {
+    pub fn new(
+        path: PathBuf,
+        stream: EventStream,
+        virt: VirtualKeyboard,
+        seat: Arc<Seat>,
+        mut config: watch::Receiver<Arc<LoadedConfig>>,
+        shutdown: watch::Receiver<bool>,
+        heartbeat: Heartbeat,
+    ) -> Self {
+        let triggers = triggers(&config.borrow_and_update());
+        Self {
+            path,
+            stream,
+            virt,
+            seat,
+            config,
+            shutdown,
+            heartbeat,
+            triggers,
+            routes: HashMap::new(),
+            frame: Vec::new(),
+            out: Vec::new(),
+            dropped: false,
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                result = self.stream.next_event() => match result {
+                    Ok(event) => self.handle_event(event),
+                    Err(e) => {
+                        error!("error reading from {}: {e}", self.path.display());
+                        break;
+                    }
+                },
+                Ok(()) = self.config.changed() => {
+                    self.triggers = triggers(&self.config.borrow_and_update());
+                }
+                _ = self.heartbeat.beat() => {}
+                Ok(()) = self.shutdown.changed() => break,
+            }
+        }
+
+        let routed: Vec<KeyCode> = self.routes.keys().copied().collect();
+        for key in routed {
+            self.release_routed(key);
+        }
+        if let Err(e) = self.virt.release_all() {
+            warn!(
+                "failed to release held buttons for {}: {e}",
+                self.path.display()
+            );
+        }
+        if let Err(e) = self.stream.device_mut().ungrab() {
+            warn!("failed to ungrab {}: {e}", self.path.display());
+        }
+    }
+
+    fn handle_event(&mut self, event: InputEvent) {
+        match event.destructure() {
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_DROPPED, _) => {
+                warn!("{} dropped events, resynchronising", self.path.display());
+                self.frame.clear();
+                self.dropped = true;
+            }
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => {
+                if std::mem::take(&mut self.dropped) {
+                    self.resync();
+                } else {
+                    self.process_frame();
+                }
+            }
+            _ if self.dropped => {}
+            _ => self.frame.push(event),
+        }
+    }
+
+    fn process_frame(&mut self) {
+        let route = self.seat.route();
+        let mut frame = std::mem::take(&mut self.frame);
+        let mut local = std::mem::take(&mut self.out);
+        local.clear();
+        let mut handed: Vec<(usize, Vec<InputEvent>)> = Vec::new();
+        let mut hand = |keyboard: usize, event: InputEvent| match handed
+            .iter_mut()
+            .find(|(k, _)| *k == keyboard)
+        {
+            Some((_, events)) => events.push(event),
+            None => handed.push((keyboard, vec![event])),
+        };
+
+        if frame
+            .iter()
+            .any(|event| event.event_type() == EventType::KEY && event.value() == 1)
+        {
+            self.seat.click();
+        }
+
+        for event in frame.drain(..) {
+            match event.destructure() {
+                EventSummary::Key(_, key, _) => {
+                    let target = match event.value() {
+                        1 if self.triggers.contains(&key) => route.inspect(|keyboard| {
+                            self.routes.insert(key, *keyboard);
+                        }),
+                        1 => None,
+                        0 => self.routes.remove(&key),
+                        _ => self.routes.get(&key).copied(),
+                    };
+                    match target {
+                        Some(keyboard) => hand(keyboard, event),
+                        None => local.push(event),
+                    }
+                }
+                EventSummary::RelativeAxis(_, axis, _) => {
+                    match (self.wheel_triggers(axis), route) {
+                        (Some((up, down)), Some(keyboard)) if is_notch_axis(axis) => {
+                            let key = if event.value() > 0 { up } else { down };
+                            for _ in 0..event.value().unsigned_abs() {
+                                hand(keyboard, InputEvent::new(EventType::KEY.0, key.code(), 1));
+                                hand(keyboard, InputEvent::new(EventType::KEY.0, key.code(), 0));
+                            }
+                        }
+                        // The notches stand in for high-resolution scrolling
+                        (Some(_), Some(_)) => {}
+                        _ => local.push(event),
+                    }
+                }
+                _ => local.push(event),
+            }
+        }
+
+        for (keyboard, events) in handed {
+            if let Err(events) = self.seat.send(keyboard, events) {
+                local.extend(events);
+            }
+        }
+        self.emit(&local);
+        self.frame = frame;
+        self.out = local;
+    }
+
+    /// Wheel pseudo-keys for the wheel `axis` belongs to, if a rule uses either.
+    fn wheel_triggers(&self, axis: RelativeAxisCode) -> Option<(KeyCode, KeyCode)> {
+        let keys = match axis {
+            RelativeAxisCode::REL_WHEEL | RelativeAxisCode::REL_WHEEL_HI_RES => {
+                (WHEEL_UP, WHEEL_DOWN)
+            }
+            RelativeAxisCode::REL_HWHEEL | RelativeAxisCode::REL_HWHEEL_HI_RES => {
+                (WHEEL_RIGHT, WHEEL_LEFT)
+            }
+            _ => return None,
+        };
+        (self.triggers.contains(&keys.0) || self.triggers.contains(&keys.1)).then_some(keys)
+    }
+
+    /// Button state is all a pointer has to lose; take it from the device.
+    fn resync(&mut self) {
+        let physical = match self.stream.device().get_key_state() {
+            Ok(physical) => physical,
+            Err(e) => {
+                warn!("failed to read key state from {}: {e}", self.path.display());
+                return;
+            }
+        };
+        let released: Vec<KeyCode> = self
+            .routes
+            .keys()
+            .filter(|key| !physical.contains(**key))
+            .copied()
+            .collect();
+        for key in released {
+            self.release_routed(key);
+        }
+
+        let mut corrections = Vec::new();
+        for key in self.virt.keys_down() {
+            if !physical.contains(*key) {
+                corrections.push(InputEvent::new(EventType::KEY.0, key.code(), 0));
+            }
+        }
+        for key in physical.iter() {
+            if !self.virt.keys_down().contains(&key) && !self.routes.contains_key(&key) {
+                corrections.push(InputEvent::new(EventType::KEY.0, key.code(), 1));
+            }
+        }
+        if !corrections.is_empty() {
+            info!(
+                "correcting {} button events after SYN_DROPPED",
+                corrections.len()
+            );
+        }
+        self.emit(&corrections);
+    }
+
+    fn release_routed(&mut self, key: KeyCode) {
+        if let Some(keyboard) = self.routes.remove(&key) {
+            let release = vec![InputEvent::new(EventType::KEY.0, key.code(), 0)];
+            let _ = self.seat.send(keyboard, release);
+        }
+    }
+
+    fn emit(&mut self, events: &[InputEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        if let Err(e) = self.virt.emit(events) {
+            error!("failed to emit events: {e}");
+            let _ = self.virt.release_all();
+        }
+    }
+}
+
+fn triggers(config: &LoadedConfig) -> HashSet<KeyCode> {
+    config.rules.iter().map(|rule| rule.from.key).collect()
+}
+
+fn is_notch_axis(axis: RelativeAxisCode) -> bool {
+    matches!(
+        axis,
+        RelativeAxisCode::REL_WHEEL | RelativeAxisCode::REL_HWHEEL
+    )
+}
@@ -0,0 +1,67 @@
+//! Giving up the privileges that are only needed to open the input devices
+//! and create the virtual ones.
+
+use tracing::info;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+const CAP_SYS_NICE: u32 = 23;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// One of the two 32-bit halves of the capability sets.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Drop every capability the process holds, bar `CAP_SYS_NICE` when
+/// `keep_nice` (for `realtime_priority`), and make sure no `exec` can ever
+/// grant any again. Capabilities belong to threads and new threads inherit
+/// them, so this has to run before any other thread is started.
+pub fn drop_capabilities(keep_nice: bool) -> anyhow::Result<()> {
+    // SAFETY: plain prctl with integer arguments.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    // SAFETY: version 3 takes two data structs, and both outlive the calls.
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if data.iter().all(|d| d.permitted == 0 && d.inheritable == 0) {
+        return Ok(());
+    }
+
+    let keep = if keep_nice { 1 << CAP_SYS_NICE } else { 0 };
+    let kept = data[0].permitted & keep;
+    data = [
+        CapData {
+            effective: kept,
+            permitted: kept,
+            inheritable: 0,
+        },
+        CapData::default(),
+    ];
+    // SAFETY: as above.
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if kept != 0 {
+        info!("dropped capabilities, keeping CAP_SYS_NICE");
+    } else {
+        info!("dropped capabilities");
+    }
+    Ok(())
+}
@@ -1,50 +1,167 @@
-use crate::config::RemapRule;
+use crate::config::{Hotstring, LoadedConfig, Modifier, RemapRule, SecureInput, TypedKey};
+use crate::control::Event;
 use crate::window::SharedActiveWindow;
-use evdev::{EventType, InputEvent, Key};
-use std::collections::HashSet;
+use evdev::{AttributeSetRef, EventType, InputEvent, KeyCode, SynchronizationCode};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::warn;
 
 const KEY_PRESS: i32 = 1;
 const KEY_RELEASE: i32 = 0;
+const KEY_REPEAT: i32 = 2;
 
-const LEFT_RIGHT_MODIFIER_PAIRS: &[(Key, Key)] = &[
-    (Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL),
-    (Key::KEY_LEFTSHIFT, Key::KEY_RIGHTSHIFT),
-    (Key::KEY_LEFTALT, Key::KEY_RIGHTALT),
-    (Key::KEY_LEFTMETA, Key::KEY_RIGHTMETA),
+const LEFT_RIGHT_MODIFIER_PAIRS: &[(KeyCode, KeyCode)] = &[
+    (KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL),
+    (KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT),
+    (KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT),
+    (KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA),
+];
+
+/// Keys that move the cursor or otherwise end what is being typed, so an
+/// abbreviation can't be completed across them.
+const BREAKS_TYPING: &[KeyCode] = &[
+    KeyCode::KEY_ENTER,
+    KeyCode::KEY_KPENTER,
+    KeyCode::KEY_TAB,
+    KeyCode::KEY_ESC,
+    KeyCode::KEY_DELETE,
+    KeyCode::KEY_CAPSLOCK,
+    KeyCode::KEY_UP,
+    KeyCode::KEY_DOWN,
+    KeyCode::KEY_LEFT,
+    KeyCode::KEY_RIGHT,
+    KeyCode::KEY_HOME,
+    KeyCode::KEY_END,
+    KeyCode::KEY_PAGEUP,
+    KeyCode::KEY_PAGEDOWN,
 ];
 
 pub struct Remapper {
     rules: Vec<RemapRule>,
+    hotstrings: Vec<Hotstring>,
+    /// The last few keys typed, at most as many as the longest abbreviation
+    typed: VecDeque<TypedKey>,
+    longest_abbr: usize,
+    /// Activation of the window `typed` was typed into; a new one means
+    /// focus moved
+    typed_in: Option<u64>,
+    /// Set while the space that expanded a hotstring is still down
+    swallowed_space: bool,
+    secure_input: SecureInput,
+    /// Whether a secure-input window had focus at the last `refresh_secure`
+    secure: bool,
     active_window: SharedActiveWindow,
-    pressed_keys: HashSet<Key>,
+    pressed_keys: HashSet<KeyCode>,
+    /// Rule (index into `rules`) each physical key was remapped by when pressed
+    active: HashMap<KeyCode, usize>,
     copilot_as_meta: bool,
     copilot_held: bool,
     /// Shift press event buffered while waiting to see if Assistant follows
     pending_shift: Option<InputEvent>,
+    events: broadcast::Sender<Event>,
 }
 
 impl Remapper {
     pub fn new(
-        rules: Vec<RemapRule>,
+        config: &LoadedConfig,
         active_window: SharedActiveWindow,
-        copilot_as_meta: bool,
+        events: broadcast::Sender<Event>,
     ) -> Self {
         Self {
-            rules,
+            rules: config.rules.clone(),
+            hotstrings: config.hotstrings.clone(),
+            typed: VecDeque::new(),
+            longest_abbr: longest_abbr(&config.hotstrings),
+            typed_in: None,
+            swallowed_space: false,
+            secure_input: config.secure_input.clone(),
+            secure: false,
             active_window,
             pressed_keys: HashSet::new(),
-            copilot_as_meta,
+            active: HashMap::new(),
+            copilot_as_meta: config.copilot_as_meta,
             copilot_held: false,
             pending_shift: None,
+            events,
+        }
+    }
+
+    pub fn set_config(&mut self, config: &LoadedConfig) {
+        self.rules = config.rules.clone();
+        self.hotstrings = config.hotstrings.clone();
+        self.typed.clear();
+        self.longest_abbr = longest_abbr(&self.hotstrings);
+        self.secure_input = config.secure_input.clone();
+        self.active.clear();
+        self.copilot_as_meta = config.copilot_as_meta;
+    }
+
+    /// Forget all in-flight state and take the physical key state as the truth.
+    pub fn reset(&mut self, physical: &AttributeSetRef<KeyCode>) {
+        self.pressed_keys = physical.iter().collect();
+        self.active.clear();
+        self.copilot_held = false;
+        self.pending_shift = None;
+        self.typed.clear();
+        self.swallowed_space = false;
+    }
+
+    /// Forget what has been typed, e.g. because a click may have moved the
+    /// caret.
+    pub fn forget_typed(&mut self) {
+        self.typed.clear();
+    }
+
+    /// Release, through the normal remapping path, any key we think is held
+    /// but that is no longer down on the physical device.
+    pub fn release_stale(
+        &mut self,
+        physical: &AttributeSetRef<KeyCode>,
+        out: &mut Vec<InputEvent>,
+    ) {
+        let stale: Vec<KeyCode> = self
+            .pressed_keys
+            .iter()
+            .filter(|k| !physical.contains(**k))
+            .copied()
+            .collect();
+
+        let secure = self.secure();
+        for key in stale {
+            if secure {
+                warn!("releasing stuck key");
+            } else {
+                warn!(?key, "releasing stuck key");
+            }
+            self.process_event(key_event(key, KEY_RELEASE), out);
+        }
+    }
+
+    /// Bring our idea of what is held in line with the physical key state,
+    /// e.g. after SYN_DROPPED, by running the missed releases and then the
+    /// missed presses through the normal remapping path.
+    pub fn resync(&mut self, physical: &AttributeSetRef<KeyCode>, out: &mut Vec<InputEvent>) {
+        self.release_stale(physical, out);
+
+        let missed: Vec<KeyCode> = physical
+            .iter()
+            .filter(|k| !self.pressed_keys.contains(k))
+            .collect();
+        for key in missed {
+            self.process_event(key_event(key, KEY_PRESS), out);
         }
     }
 
-    pub fn process_event(&mut self, event: InputEvent) -> Vec<InputEvent> {
+    /// Remap one event, appending the result to `out`. Doesn't allocate on
+    /// the common path so it can sit on the latency-sensitive read loop.
+    pub fn process_event(&mut self, event: InputEvent, out: &mut Vec<InputEvent>) {
         if event.event_type() != EventType::KEY {
-            return vec![event];
+            out.push(event);
+            return;
         }
 
-        let key = Key::new(event.code());
+        let key = KeyCode::new(event.code());
         let value = event.value();
 
         match value {
@@ -53,98 +170,225 @@ impl Remapper {
             }
             KEY_RELEASE => {
                 self.pressed_keys.remove(&key);
+                if key == KeyCode::KEY_SPACE && std::mem::take(&mut self.swallowed_space) {
+                    return;
+                }
             }
             _ => {
-                if self.copilot_as_meta && self.copilot_held && key == Key::KEY_F23 {
-                    return vec![];
+                if key == KeyCode::KEY_SPACE && self.swallowed_space {
+                    return;
                 }
-                if self.copilot_as_meta && self.pending_shift.is_some() {
-                    if matches!(key, Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT) {
-                        return vec![];
-                    }
+                // Repeat whatever the key was remapped to when it went down
+                if let Some(&rule) = self.active.get(&key) {
+                    out.push(key_event(self.rules[rule].to.key, KEY_REPEAT));
+                    return;
+                }
+                if self.copilot_as_meta && self.copilot_held && key == KeyCode::KEY_F23 {
+                    return;
+                }
+                if self.copilot_as_meta
+                    && self.pending_shift.is_some()
+                    && matches!(key, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT)
+                {
+                    return;
                 }
-                return vec![event];
+                // A held key types or deletes more than the one keystroke
+                // the buffer has for it
+                if other_side(key).is_none() {
+                    self.typed.clear();
+                }
+                out.push(event);
+                return;
             }
         }
 
-        if self.copilot_as_meta {
-            if let Some(events) = self.handle_copilot(key, value) {
-                return events;
-            }
+        // A Copilot chord already under way is finished even in a
+        // secure-input window, so Shift and Assistant don't get stuck
+        if self.copilot_as_meta
+            && (!self.secure || self.copilot_held || self.pending_shift.is_some())
+            && self.handle_copilot(key, value, out)
+        {
+            return;
         }
 
-        if let Some(rule) = self.find_matching_rule(key) {
-            self.apply_remap(&rule, key, value)
-        } else {
-            vec![event]
-        }
+        self.remap_key(key, value, out);
     }
 
-    fn handle_copilot(&mut self, key: Key, value: i32) -> Option<Vec<InputEvent>> {
+    /// Returns true if the event was consumed by Copilot key handling.
+    fn handle_copilot(&mut self, key: KeyCode, value: i32, out: &mut Vec<InputEvent>) -> bool {
         // When Meta is held and Shift is pressed, buffer it
-        if matches!(key, Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT)
+        if matches!(key, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT)
             && value == KEY_PRESS
-            && self.is_modifier_held(Key::KEY_LEFTMETA)
+            && self.is_modifier_held(Modifier {
+                key: KeyCode::KEY_LEFTMETA,
+                either_side: true,
+            })
             && !self.copilot_held
         {
-            self.pending_shift = Some(InputEvent::new(EventType::KEY, key.code(), value));
-            return Some(vec![]);
+            self.pending_shift = Some(InputEvent::new(EventType::KEY.0, key.code(), value));
+            return true;
         }
 
         // Assistant arrives while Shift is buffered: it's the Copilot key
-        if key == Key::KEY_F23 && value == KEY_PRESS && self.pending_shift.is_some() {
+        if key == KeyCode::KEY_F23 && value == KEY_PRESS && self.pending_shift.is_some() {
             self.pending_shift = None;
             self.copilot_held = true;
-            self.pressed_keys.remove(&Key::KEY_LEFTSHIFT);
-            self.pressed_keys.remove(&Key::KEY_RIGHTSHIFT);
-            self.pressed_keys.remove(&Key::KEY_F23);
-            return Some(vec![]);
+            self.pressed_keys.remove(&KeyCode::KEY_LEFTSHIFT);
+            self.pressed_keys.remove(&KeyCode::KEY_RIGHTSHIFT);
+            self.pressed_keys.remove(&KeyCode::KEY_F23);
+            return true;
         }
 
         // Any other key while Shift is buffered: flush the buffered Shift first
         if let Some(shift_event) = self.pending_shift.take() {
-            let mut events = vec![shift_event, syn_event()];
-            if let Some(mut more) = self.process_non_copilot(key, value) {
-                events.append(&mut more);
-            }
-            return Some(events);
+            out.push(shift_event);
+            self.remap_key(key, value, out);
+            return true;
         }
 
-        if key == Key::KEY_F23 && value == KEY_RELEASE && self.copilot_held {
+        if key == KeyCode::KEY_F23 && value == KEY_RELEASE && self.copilot_held {
             self.copilot_held = false;
-            return Some(vec![]);
+            return true;
         }
 
         // Suppress Shift and Assistant events while Copilot is held
-        if self.copilot_held
-            && matches!(key, Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT | Key::KEY_F23)
-        {
-            return Some(vec![]);
-        }
+        self.copilot_held
+            && matches!(
+                key,
+                KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT | KeyCode::KEY_F23
+            )
+    }
 
-        None
+    fn remap_key(&mut self, key: KeyCode, value: i32, out: &mut Vec<InputEvent>) {
+        // A release undoes exactly what the press did, whatever the modifiers
+        // or the focused window are by now.
+        let rule = if value == KEY_RELEASE {
+            self.active.remove(&key)
+        } else {
+            self.find_matching_rule(key)
+        };
+
+        match rule {
+            Some(rule) => {
+                if value == KEY_PRESS {
+                    self.active.insert(key, rule);
+                    self.typed.clear();
+                }
+                self.apply_remap(rule, value, out);
+            }
+            None if value == KEY_PRESS && self.expand_hotstring(key, out) => {
+                self.swallowed_space = true;
+            }
+            None => out.push(key_event(key, value)),
+        }
     }
 
-    fn process_non_copilot(&mut self, key: Key, value: i32) -> Option<Vec<InputEvent>> {
-        if let Some(rule) = self.find_matching_rule(key) {
-            Some(self.apply_remap(&rule, key, value))
+    /// Note a key typed; on a space that completes an abbreviation, type the
+    /// expansion in place of the abbreviation and the space.
+    fn expand_hotstring(&mut self, key: KeyCode, out: &mut Vec<InputEvent>) -> bool {
+        if self.hotstrings.is_empty() {
+            return false;
+        }
+        if self.secure {
+            self.typed.clear();
+            return false;
+        }
+        let window_class = self.window_class();
+        let activation = self.activation();
+        if self.typed_in != Some(activation) {
+            self.typed.clear();
+            self.typed_in = Some(activation);
+        }
+
+        let command_held = self.pressed_keys.iter().any(|k| {
+            other_side(*k).is_some()
+                && !matches!(*k, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT)
+        });
+        if key == KeyCode::KEY_SPACE && !command_held {
+            let found = self.hotstrings.iter().position(|hotstring| {
+                self.typed
+                    .iter()
+                    .rev()
+                    .take(hotstring.abbr.len())
+                    .eq(hotstring.abbr.iter().rev())
+                    && applies_to(&hotstring.include, &hotstring.exclude, &window_class)
+            });
+            self.typed.clear();
+            if let Some(index) = found {
+                self.type_expansion(index, out);
+                return true;
+            }
+            return false;
+        }
+
+        if key == KeyCode::KEY_BACKSPACE {
+            self.typed.pop_back();
+        } else if other_side(key).is_some() {
+            // Shift is picked up with the key it is held for
+        } else if command_held
+            || BREAKS_TYPING.contains(&key)
+            || key.code() >= KeyCode::BTN_0.code()
+        {
+            self.typed.clear();
         } else {
-            Some(vec![
-                InputEvent::new(EventType::KEY, key.code(), value),
-                syn_event(),
-            ])
+            if self.typed.len() == self.longest_abbr {
+                self.typed.pop_front();
+            }
+            let shifted = self.pressed_keys.contains(&KeyCode::KEY_LEFTSHIFT)
+                || self.pressed_keys.contains(&KeyCode::KEY_RIGHTSHIFT);
+            self.typed.push_back((key, shifted));
         }
+        false
     }
 
-    fn find_matching_rule(&self, trigger_key: Key) -> Option<RemapRule> {
-        let window_class = self
-            .active_window
-            .try_read()
-            .ok()
-            .and_then(|w| w.as_ref().map(|w| w.resource_class.clone()))
-            .unwrap_or_default();
+    /// Backspace over the abbreviation and type the expansion, one key per
+    /// frame, with any held modifiers lifted for the duration. The frames
+    /// are split by SYN_REPORTs, for the device task to emit a few at a time.
+    fn type_expansion(&self, index: usize, out: &mut Vec<InputEvent>) {
+        let hotstring = &self.hotstrings[index];
+        let held: Vec<KeyCode> = self
+            .pressed_keys
+            .iter()
+            .copied()
+            .filter(|key| other_side(*key).is_some())
+            .collect();
+
+        if !held.is_empty() {
+            for key in &held {
+                out.push(key_event(*key, KEY_RELEASE));
+            }
+            out.push(syn_report());
+        }
+        let backspaces =
+            std::iter::repeat_n(&(KeyCode::KEY_BACKSPACE, false), hotstring.abbr.len());
+        for &(key, shifted) in backspaces.chain(&hotstring.text) {
+            if shifted {
+                out.push(key_event(KeyCode::KEY_LEFTSHIFT, KEY_PRESS));
+                out.push(syn_report());
+            }
+            out.push(key_event(key, KEY_PRESS));
+            out.push(syn_report());
+            out.push(key_event(key, KEY_RELEASE));
+            out.push(syn_report());
+            if shifted {
+                out.push(key_event(KeyCode::KEY_LEFTSHIFT, KEY_RELEASE));
+                out.push(syn_report());
+            }
+        }
+        // The frame's own SYN_REPORT ends the last one
+        out.pop();
+        for key in &held {
+            out.push(key_event(*key, KEY_PRESS));
+        }
+    }
+
+    fn find_matching_rule(&self, trigger_key: KeyCode) -> Option<usize> {
+        if self.secure {
+            return None;
+        }
+        let window_class = self.window_class();
 
-        for rule in &self.rules {
+        for (index, rule) in self.rules.iter().enumerate() {
             if rule.from.key != trigger_key {
                 continue;
             }
@@ -158,70 +402,116 @@ impl Remapper {
                 continue;
             }
 
-            if !rule.include.is_empty() {
-                let included = rule
-                    .include
-                    .iter()
-                    .any(|inc| window_class.eq_ignore_ascii_case(inc));
-                if !included {
-                    continue;
-                }
+            if rule.strict && self.extra_modifier_held(rule) {
+                continue;
             }
 
-            let excluded = rule
-                .exclude
-                .iter()
-                .any(|exc| window_class.eq_ignore_ascii_case(exc));
-            if excluded {
+            if !applies_to(&rule.include, &rule.exclude, &window_class) {
                 continue;
             }
 
-            return Some(rule.clone());
+            return Some(index);
         }
 
         None
     }
 
-    fn is_modifier_held(&self, modifier: Key) -> bool {
-        if self.pressed_keys.contains(&modifier) {
-            return true;
-        }
-        for (left, right) in LEFT_RIGHT_MODIFIER_PAIRS {
-            if modifier == *left && self.pressed_keys.contains(right) {
-                return true;
-            }
-            if modifier == *right && self.pressed_keys.contains(left) {
-                return true;
-            }
-        }
-        false
+    /// Whether a secure-input window has focus: nothing is remapped, and
+    /// nothing that could reveal what is typed is logged or recorded.
+    pub fn secure(&self) -> bool {
+        self.secure
     }
 
-    fn apply_remap(&self, rule: &RemapRule, _trigger_key: Key, value: i32) -> Vec<InputEvent> {
-        let mut events = Vec::new();
+    /// Look up whether a secure-input window has focus, for the events about
+    /// to be remapped. While the window is being updated and can't be read,
+    /// it is taken to be one.
+    pub fn refresh_secure(&mut self) -> bool {
+        self.secure = self.active_window.try_read().map_or(true, |window| {
+            window
+                .as_ref()
+                .is_some_and(|w| self.secure_input.matches(&w.resource_class, &w.caption))
+        });
+        self.secure
+    }
 
-        if value == KEY_PRESS {
-            // Release the "from" modifiers that aren't in "to"
-            for from_mod in &rule.from.modifiers {
-                let needed_in_to = rule
-                    .to
+    fn window_class(&self) -> Arc<str> {
+        self.active_window
+            .try_read()
+            .ok()
+            .and_then(|w| w.as_ref().map(|w| w.resource_class.clone()))
+            .unwrap_or_default()
+    }
+
+    fn activation(&self) -> u64 {
+        self.active_window
+            .try_read()
+            .ok()
+            .and_then(|w| w.as_ref().map(|w| w.activation))
+            .unwrap_or_default()
+    }
+
+    /// Whether any ctrl, shift, alt or super key is physically down.
+    pub fn modifiers_held(&self) -> bool {
+        self.pressed_keys
+            .iter()
+            .any(|key| other_side(*key).is_some())
+    }
+
+    fn is_modifier_held(&self, modifier: Modifier) -> bool {
+        self.held_keys(&modifier).next().is_some()
+    }
+
+    /// The concrete keycodes physically down that satisfy `modifier`.
+    fn held_keys<'a>(&'a self, modifier: &'a Modifier) -> impl Iterator<Item = KeyCode> + 'a {
+        self.pressed_keys
+            .iter()
+            .copied()
+            .filter(move |key| satisfies(modifier, *key))
+    }
+
+    /// Whether a modifier that is neither in `from` nor in `passthrough` is held.
+    fn extra_modifier_held(&self, rule: &RemapRule) -> bool {
+        self.pressed_keys.iter().any(|key| {
+            other_side(*key).is_some()
+                && !rule
+                    .from
                     .modifiers
                     .iter()
-                    .any(|to_mod| self.same_modifier_group(*from_mod, *to_mod));
-                if !needed_in_to {
-                    events.push(key_event(*from_mod, KEY_RELEASE));
+                    .chain(&rule.passthrough)
+                    .any(|m| satisfies(m, *key))
+        })
+    }
+
+    fn apply_remap(&self, rule: usize, value: i32, events: &mut Vec<InputEvent>) {
+        let rule = &self.rules[rule];
+
+        if value == KEY_PRESS {
+            let _ = self.events.send(Event::RuleFired {
+                from: rule.from.name.clone(),
+                to: rule.to.name.clone(),
+                resource_class: self.window_class(),
+            });
+
+            // Release the held "from" modifier keys that don't also give a
+            // "to" modifier, which a sided one only does on its own side
+            for from_mod in &rule.from.modifiers {
+                for key in self.held_keys(from_mod) {
+                    if !rule
+                        .to
+                        .modifiers
+                        .iter()
+                        .any(|to_mod| satisfies(to_mod, key))
+                    {
+                        events.push(key_event(key, KEY_RELEASE));
+                    }
                 }
             }
 
-            // Press the "to" modifiers that aren't already held from "from"
+            // Press the "to" modifiers no held key gives already, from
+            // "from" or because they are held anyway
             for to_mod in &rule.to.modifiers {
-                let already_from = rule
-                    .from
-                    .modifiers
-                    .iter()
-                    .any(|from_mod| self.same_modifier_group(*from_mod, *to_mod));
-                if !already_from {
-                    events.push(key_event(*to_mod, KEY_PRESS));
+                if self.held_keys(to_mod).next().is_none() {
+                    events.push(key_event(to_mod.key, KEY_PRESS));
                 }
             }
 
@@ -229,51 +519,569 @@ impl Remapper {
         } else {
             events.push(key_event(rule.to.key, KEY_RELEASE));
 
-            // Release "to" modifiers we injected, re-press "from" modifiers
+            // Release "to" modifiers we injected, unless that very key is
+            // physically down by now, and re-press "from" modifiers. The
+            // kernel drops the release of one that was never pressed.
             for to_mod in &rule.to.modifiers {
-                let was_from = rule
-                    .from
-                    .modifiers
-                    .iter()
-                    .any(|from_mod| self.same_modifier_group(*from_mod, *to_mod));
-                if !was_from {
-                    events.push(key_event(*to_mod, KEY_RELEASE));
+                if !self.pressed_keys.contains(&to_mod.key) {
+                    events.push(key_event(to_mod.key, KEY_RELEASE));
                 }
             }
 
+            // ...but only those still physically held, or they'd get stuck
             for from_mod in &rule.from.modifiers {
-                let is_to = rule
-                    .to
-                    .modifiers
-                    .iter()
-                    .any(|to_mod| self.same_modifier_group(*from_mod, *to_mod));
-                if !is_to {
-                    events.push(key_event(*from_mod, KEY_PRESS));
+                for key in self.held_keys(from_mod) {
+                    if !rule
+                        .to
+                        .modifiers
+                        .iter()
+                        .any(|to_mod| satisfies(to_mod, key))
+                    {
+                        events.push(key_event(key, KEY_PRESS));
+                    }
                 }
             }
         }
+    }
+}
+
+/// Whether a rule or hotstring scoped by `include` and `exclude` applies in
+/// a window of class `window_class`.
+fn applies_to(include: &[String], exclude: &[String], window_class: &str) -> bool {
+    let included = include.is_empty()
+        || include
+            .iter()
+            .any(|inc| window_class.eq_ignore_ascii_case(inc));
+    included
+        && !exclude
+            .iter()
+            .any(|exc| window_class.eq_ignore_ascii_case(exc))
+}
+
+fn longest_abbr(hotstrings: &[Hotstring]) -> usize {
+    hotstrings.iter().map(|h| h.abbr.len()).max().unwrap_or(0)
+}
+
+fn satisfies(modifier: &Modifier, key: KeyCode) -> bool {
+    key == modifier.key || (modifier.either_side && other_side(modifier.key) == Some(key))
+}
+
+/// The same modifier on the other side of the keyboard.
+fn other_side(key: KeyCode) -> Option<KeyCode> {
+    LEFT_RIGHT_MODIFIER_PAIRS.iter().find_map(|(left, right)| {
+        if key == *left {
+            Some(*right)
+        } else if key == *right {
+            Some(*left)
+        } else {
+            None
+        }
+    })
+}
+
+fn key_event(key: KeyCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, key.code(), value)
+}
+
+/// Ends a frame early, for keys that must reach clients one at a time.
+fn syn_report() -> InputEvent {
+    InputEvent::new(
+        EventType::SYNCHRONIZATION.0,
+        SynchronizationCode::SYN_REPORT.0,
+        0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::window::{ActiveWindow, shared_active_window};
+
+    const CONFIG: &str = r#"
+copilot_as_meta = true
+secure_input = ["pinentry-qt"]
+
+[[remap]]
+from = "super+c"
+to = "ctrl+c"
 
-        events.push(syn_event());
-        events
+[[hotstring]]
+abbr = ";sig"
+text = "Hi"
+"#;
+
+    fn remapper() -> (Remapper, SharedActiveWindow) {
+        remapper_with(CONFIG)
     }
 
-    fn same_modifier_group(&self, a: Key, b: Key) -> bool {
-        if a == b {
-            return true;
+    fn remapper_with(config: &str) -> (Remapper, SharedActiveWindow) {
+        let window = shared_active_window();
+        let (events, _) = broadcast::channel(16);
+        let config = parse_config(config).unwrap();
+        (Remapper::new(&config, window.clone(), events), window)
+    }
+
+    fn focus(window: &SharedActiveWindow, resource_class: &str) {
+        let mut window = window.try_write().unwrap();
+        let activation = window.as_ref().map_or(0, |w| w.activation);
+        *window = Some(ActiveWindow {
+            resource_class: resource_class.into(),
+            caption: "".into(),
+            activation: activation + 1,
+        });
+    }
+
+    fn retitle(window: &SharedActiveWindow, caption: &str) {
+        let mut window = window.try_write().unwrap();
+        let window = window.as_mut().unwrap();
+        window.caption = caption.into();
+        window.resource_class = window.resource_class.to_string().into();
+    }
+
+    fn feed(remapper: &mut Remapper, events: &[(KeyCode, i32)]) -> Vec<(KeyCode, i32)> {
+        let mut out = Vec::new();
+        for &(key, value) in events {
+            remapper.process_event(key_event(key, value), &mut out);
         }
-        for (left, right) in LEFT_RIGHT_MODIFIER_PAIRS {
-            if (a == *left || a == *right) && (b == *left || b == *right) {
-                return true;
-            }
+        out.iter()
+            .map(|e| (KeyCode::new(e.code()), e.value()))
+            .collect()
+    }
+
+    #[test]
+    fn rules_do_not_apply_in_secure_windows() {
+        let (mut remapper, window) = remapper();
+        let super_c = [(KeyCode::KEY_LEFTMETA, 1), (KeyCode::KEY_C, 1)];
+
+        focus(&window, "pinentry-qt");
+        assert!(remapper.refresh_secure());
+        assert_eq!(feed(&mut remapper, &super_c), super_c);
+        feed(
+            &mut remapper,
+            &[(KeyCode::KEY_C, 0), (KeyCode::KEY_LEFTMETA, 0)],
+        );
+
+        focus(&window, "firefox");
+        assert!(!remapper.refresh_secure());
+        assert_eq!(
+            feed(&mut remapper, &super_c),
+            [
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_C, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_window_being_updated_counts_as_secure() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "firefox");
+        let updating = window.try_write().unwrap();
+        assert!(remapper.refresh_secure());
+        drop(updating);
+        assert!(!remapper.refresh_secure());
+    }
+
+    #[test]
+    fn copilot_key_passes_through_in_secure_windows() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "pinentry-qt");
+        remapper.refresh_secure();
+        let copilot = [
+            (KeyCode::KEY_LEFTMETA, 1),
+            (KeyCode::KEY_LEFTSHIFT, 1),
+            (KeyCode::KEY_F23, 1),
+        ];
+        assert_eq!(feed(&mut remapper, &copilot), copilot);
+    }
+
+    /// Press and release each of `keys` in turn.
+    fn taps(keys: &[KeyCode]) -> Vec<(KeyCode, i32)> {
+        keys.iter().flat_map(|key| [(*key, 1), (*key, 0)]).collect()
+    }
+
+    const SIG: [KeyCode; 4] = [
+        KeyCode::KEY_SEMICOLON,
+        KeyCode::KEY_S,
+        KeyCode::KEY_I,
+        KeyCode::KEY_G,
+    ];
+
+    /// Whether pressing space now expands the hotstring.
+    fn expands(remapper: &mut Remapper) -> bool {
+        feed(remapper, &[(KeyCode::KEY_SPACE, 1)]) != [(KeyCode::KEY_SPACE, 1)]
+    }
+
+    #[test]
+    fn abbreviation_and_space_type_the_expansion() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "firefox");
+        remapper.refresh_secure();
+        feed(&mut remapper, &taps(&SIG));
+
+        let mut out = Vec::new();
+        remapper.process_event(key_event(KeyCode::KEY_SPACE, KEY_PRESS), &mut out);
+        // One key per frame, the last ended by the frame's own SYN_REPORT
+        let frames: Vec<Vec<(KeyCode, i32)>> = out
+            .split(|event| event.event_type() == EventType::SYNCHRONIZATION)
+            .map(|frame| {
+                frame
+                    .iter()
+                    .map(|e| (KeyCode::new(e.code()), e.value()))
+                    .collect()
+            })
+            .collect();
+        let mut expected = Vec::new();
+        for _ in SIG {
+            expected.extend([
+                vec![(KeyCode::KEY_BACKSPACE, 1)],
+                vec![(KeyCode::KEY_BACKSPACE, 0)],
+            ]);
         }
-        false
+        expected.extend([
+            vec![(KeyCode::KEY_LEFTSHIFT, 1)],
+            vec![(KeyCode::KEY_H, 1)],
+            vec![(KeyCode::KEY_H, 0)],
+            vec![(KeyCode::KEY_LEFTSHIFT, 0)],
+            vec![(KeyCode::KEY_I, 1)],
+            vec![(KeyCode::KEY_I, 0)],
+        ]);
+        assert_eq!(frames, expected);
+        // The space that expanded it doesn't follow
+        assert!(feed(&mut remapper, &[(KeyCode::KEY_SPACE, 0)]).is_empty());
     }
-}
 
-fn key_event(key: Key, value: i32) -> InputEvent {
-    InputEvent::new(EventType::KEY, key.code(), value)
-}
+    #[test]
+    fn backspace_takes_back_a_key() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "firefox");
+        remapper.refresh_secure();
+        feed(&mut remapper, &taps(&SIG[..3]));
+        feed(
+            &mut remapper,
+            &taps(&[KeyCode::KEY_X, KeyCode::KEY_BACKSPACE]),
+        );
+        feed(&mut remapper, &taps(&SIG[3..]));
+        assert!(expands(&mut remapper));
+    }
+
+    #[test]
+    fn navigation_focus_and_clicks_forget_what_was_typed() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "firefox");
+        remapper.refresh_secure();
+
+        feed(&mut remapper, &taps(&SIG[..3]));
+        feed(&mut remapper, &taps(&[KeyCode::KEY_LEFT]));
+        feed(&mut remapper, &taps(&SIG[3..]));
+        assert!(!expands(&mut remapper));
+        feed(&mut remapper, &[(KeyCode::KEY_SPACE, 0)]);
+
+        feed(&mut remapper, &taps(&SIG[..3]));
+        // Another window, even of the same class
+        focus(&window, "firefox");
+        feed(&mut remapper, &taps(&SIG[3..]));
+        assert!(!expands(&mut remapper));
+        feed(&mut remapper, &[(KeyCode::KEY_SPACE, 0)]);
+
+        feed(&mut remapper, &taps(&SIG[..3]));
+        remapper.forget_typed();
+        feed(&mut remapper, &taps(&SIG[3..]));
+        assert!(!expands(&mut remapper));
+        feed(&mut remapper, &[(KeyCode::KEY_SPACE, 0)]);
+
+        // Holding g types more than one of it
+        feed(&mut remapper, &taps(&SIG[..3]));
+        feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_G, 1),
+                (KeyCode::KEY_G, 2),
+                (KeyCode::KEY_G, 0),
+            ],
+        );
+        assert!(!expands(&mut remapper));
+    }
+
+    #[test]
+    fn a_new_title_is_not_a_new_window() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "org.kde.kate");
+        feed(&mut remapper, &taps(&SIG[..2]));
+        // Editors mark the document modified as soon as it is
+        retitle(&window, "Untitled * — Kate");
+        feed(&mut remapper, &taps(&SIG[2..]));
+        assert!(expands(&mut remapper));
+    }
+
+    #[test]
+    fn held_shift_does_not_forget_what_was_typed() {
+        let (mut remapper, window) = remapper();
+        focus(&window, "firefox");
+        feed(&mut remapper, &taps(&SIG[..2]));
+        feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTSHIFT, 1),
+                (KeyCode::KEY_LEFTSHIFT, 2),
+                (KeyCode::KEY_LEFTSHIFT, 0),
+            ],
+        );
+        feed(&mut remapper, &taps(&SIG[2..]));
+        assert!(expands(&mut remapper));
+    }
+
+    #[test]
+    fn to_modifiers_held_anyway_are_left_alone() {
+        let (mut remapper, _) = remapper();
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_C, 0),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_C, 0),
+                (KeyCode::KEY_LEFTMETA, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn sided_to_modifiers_come_out_on_their_own_side() {
+        let (mut remapper, _) = remapper_with(
+            "[[remap]]\nfrom = \"ctrl+c\"\nto = \"lctrl+x\"\n\n\
+             [[remap]]\nfrom = \"ctrl+v\"\nto = \"ctrl+y\"\n",
+        );
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_RIGHTCTRL, 1),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_C, 0),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_RIGHTCTRL, 1),
+                (KeyCode::KEY_RIGHTCTRL, 0),
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_X, 1),
+                (KeyCode::KEY_X, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_RIGHTCTRL, 1),
+            ]
+        );
+
+        // Either side will do for a plain ctrl, so it stays as it is
+        let out = feed(&mut remapper, &[(KeyCode::KEY_V, 1)]);
+        assert_eq!(out, [(KeyCode::KEY_Y, 1)]);
+    }
+
+    #[test]
+    fn a_release_undoes_the_press_after_the_modifier_is_let_go() {
+        let (mut remapper, _) = remapper();
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_C, 0),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_C, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+            ]
+        );
+    }
 
-fn syn_event() -> InputEvent {
-    InputEvent::new(EventType::SYNCHRONIZATION, 0, 0)
+    #[test]
+    fn a_release_undoes_the_press_after_focus_moves() {
+        let (mut remapper, window) = remapper_with(
+            "[[remap]]\nfrom = \"super+c\"\nto = \"ctrl+c\"\ninclude = [\"firefox\"]\n",
+        );
+        focus(&window, "firefox");
+        feed(
+            &mut remapper,
+            &[(KeyCode::KEY_LEFTMETA, 1), (KeyCode::KEY_C, 1)],
+        );
+
+        // The rule no longer applies, but the release still matches the press
+        focus(&window, "org.kde.konsole");
+        assert_eq!(
+            feed(&mut remapper, &[(KeyCode::KEY_C, 0)]),
+            [
+                (KeyCode::KEY_C, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_LEFTMETA, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_remapped_key_repeats_as_what_it_was_remapped_to() {
+        let (mut remapper, _) = remapper_with(
+            "[[remap]]\nfrom = \"super+backspace\"\nto = \"ctrl+backspace\"\n\n\
+             [[remap]]\nfrom = \"alt+h\"\nto = \"left\"\n",
+        );
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_BACKSPACE, 1),
+                (KeyCode::KEY_BACKSPACE, 2),
+                (KeyCode::KEY_BACKSPACE, 2),
+                (KeyCode::KEY_BACKSPACE, 0),
+                (KeyCode::KEY_LEFTMETA, 0),
+            ],
+        );
+        // Ctrl stays held for the repeats, and super doesn't come back
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_BACKSPACE, 1),
+                (KeyCode::KEY_BACKSPACE, 2),
+                (KeyCode::KEY_BACKSPACE, 2),
+                (KeyCode::KEY_BACKSPACE, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+            ]
+        );
+
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTALT, 1),
+                (KeyCode::KEY_H, 1),
+                (KeyCode::KEY_H, 2),
+                (KeyCode::KEY_H, 0),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_LEFTALT, 1),
+                (KeyCode::KEY_LEFTALT, 0),
+                (KeyCode::KEY_LEFT, 1),
+                (KeyCode::KEY_LEFT, 2),
+                (KeyCode::KEY_LEFT, 0),
+                (KeyCode::KEY_LEFTALT, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_rules_skip_extra_modifiers_but_keep_passthrough_held() {
+        let (mut remapper, _) = remapper_with(
+            "[[remap]]\nfrom = \"super+c\"\nto = \"ctrl+c\"\nstrict = true\n\n\
+             [[remap]]\nfrom = \"super+v\"\nto = \"ctrl+v\"\nstrict = true\n\
+             passthrough = [\"shift\"]\n",
+        );
+        let super_shift_c = [
+            (KeyCode::KEY_LEFTMETA, 1),
+            (KeyCode::KEY_LEFTSHIFT, 1),
+            (KeyCode::KEY_C, 1),
+            (KeyCode::KEY_C, 0),
+        ];
+        assert_eq!(feed(&mut remapper, &super_shift_c), super_shift_c);
+
+        // Without the shift it still fires
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTSHIFT, 0),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_C, 0),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_LEFTSHIFT, 0),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_C, 1),
+                (KeyCode::KEY_C, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_LEFTMETA, 1),
+            ]
+        );
+
+        // Shift is tolerated and stays down throughout
+        let out = feed(
+            &mut remapper,
+            &[
+                (KeyCode::KEY_LEFTSHIFT, 1),
+                (KeyCode::KEY_V, 1),
+                (KeyCode::KEY_V, 0),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_LEFTSHIFT, 1),
+                (KeyCode::KEY_LEFTMETA, 0),
+                (KeyCode::KEY_LEFTCTRL, 1),
+                (KeyCode::KEY_V, 1),
+                (KeyCode::KEY_V, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_LEFTMETA, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn resync_runs_missed_releases_and_presses_through_the_rules() {
+        let (mut remapper, _) = remapper();
+        feed(
+            &mut remapper,
+            &[(KeyCode::KEY_LEFTMETA, 1), (KeyCode::KEY_C, 1)],
+        );
+
+        // C came up and X went down while events were being dropped
+        let mut physical = evdev::AttributeSet::<KeyCode>::new();
+        physical.insert(KeyCode::KEY_LEFTMETA);
+        physical.insert(KeyCode::KEY_X);
+        let mut out = Vec::new();
+        remapper.resync(&physical, &mut out);
+        let out: Vec<_> = out
+            .iter()
+            .map(|e| (KeyCode::new(e.code()), e.value()))
+            .collect();
+        assert_eq!(
+            out,
+            [
+                (KeyCode::KEY_C, 0),
+                (KeyCode::KEY_LEFTCTRL, 0),
+                (KeyCode::KEY_LEFTMETA, 1),
+                (KeyCode::KEY_X, 1),
+            ]
+        );
+    }
 }
@@ -0,0 +1,236 @@
+//! `sandbox = true`: once the devices are open, Landlock confines the daemon
+//! to the few paths it still touches, and a seccomp filter refuses the
+//! syscalls nothing in it should ever make. Both only apply to the calling
+//! thread and the threads it starts, so like `privileges` this runs before
+//! the runtime does.
+
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::Path;
+use tracing::{info, warn};
+
+pub fn apply(config_path: &Path, script_dir: &Path) {
+    match landlock(config_path, script_dir) {
+        Ok(abi) => info!("restricted filesystem access with Landlock ABI {abi}"),
+        Err(e) => warn!("could not apply Landlock rules, carrying on without: {e}"),
+    }
+    match seccomp() {
+        Ok(()) => info!("installed seccomp filter"),
+        Err(e) => warn!("could not install seccomp filter, carrying on without: {e}"),
+    }
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// Everything up to MAKE_SYM, the rights the first Landlock ABI knows about
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Read the config, write the KWin script and nothing else. Returns the
+/// Landlock ABI version the kernel speaks.
+fn landlock(config_path: &Path, script_dir: &Path) -> std::io::Result<i64> {
+    // SAFETY: asking for the ABI version takes no attributes.
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` outlives the call and its size is passed along.
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: the syscall just returned this fd and nothing else owns it.
+    let ruleset: OwnedFd = unsafe { std::os::fd::FromRawFd::from_raw_fd(ruleset as libc::c_int) };
+
+    let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    let write = ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_REMOVE_FILE;
+    let mut rules = vec![(script_dir, (read | write | ACCESS_FS_TRUNCATE) & handled)];
+    if let Some(dir) = config_path.parent() {
+        rules.push((dir, read));
+    }
+    // For reloading a config that sets `layout`
+    rules.push((Path::new("/usr/share/X11/xkb"), read));
+    // The script's directory is removed again on the way out
+    if let Some(tmp) = script_dir.parent() {
+        rules.push((tmp, ACCESS_FS_REMOVE_DIR));
+    }
+
+    for (path, access) in rules {
+        let dir = match std::fs::File::open(path) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("not allowing access to {}: {e}", path.display());
+                continue;
+            }
+        };
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: dir.as_raw_fd(),
+        };
+        // SAFETY: both fds are open and `rule` outlives the call.
+        let added = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if added < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    // SAFETY: the ruleset fd is open; this only ever narrows what we can do.
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(abi)
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls that only ever help someone who has taken over the process.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_acct,
+];
+
+/// Fail the denied syscalls with EPERM, and kill the process outright on a
+/// syscall made through another ABI, which could sidestep the list.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp() -> std::io::Result<()> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, sock_filter};
+
+    const ARCH_OFFSET: u32 = 4;
+    const NR_OFFSET: u32 = 0;
+    /// x32 syscalls are x86_64 ones with this bit set
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    let load = |offset| sock_filter {
+        code: (BPF_LD | BPF_W | BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    };
+    let jump = |op, k, jt, jf| sock_filter {
+        code: (BPF_JMP | op | BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let ret = |k| sock_filter {
+        code: (BPF_RET | BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+
+    let denied = DENIED_SYSCALLS.len() as u8;
+    let mut filter = vec![
+        load(ARCH_OFFSET),
+        jump(BPF_JEQ, AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(NR_OFFSET),
+        jump(BPF_JGE, X32_SYSCALL_BIT, 0, 1),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+    ];
+    // Each match jumps over the rest of the list and the ALLOW to the EPERM
+    for (i, nr) in DENIED_SYSCALLS.iter().enumerate() {
+        filter.push(jump(BPF_JEQ, *nr as u32, denied - i as u8, 0));
+    }
+    filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    filter.push(ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: `program` and the filter it points to outlive the call; the
+    // kernel copies them.
+    if unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp() -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "no seccomp filter for this architecture",
+    ))
+}
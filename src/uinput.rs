@@ -0,0 +1,247 @@
+//! Just enough of uinput to build a virtual device that mirrors every
+//! capability of its source, LEDs included, and to read back the LED and
+//! sound writes the compositor makes to it. evdev's own builder can do
+//! neither.
+
+use crate::helper::Opener;
+use evdev::raw_stream::RawDevice;
+use evdev::{BusType, EventType, InputEvent, InputId};
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+
+mod sys {
+    nix::ioctl_none!(ui_dev_create, b'U', 1);
+    nix::ioctl_write_ptr!(ui_dev_setup, b'U', 3, libc::uinput_setup);
+    nix::ioctl_write_ptr!(ui_abs_setup, b'U', 4, libc::uinput_abs_setup);
+    nix::ioctl_write_int!(ui_set_evbit, b'U', 100);
+    nix::ioctl_write_int!(ui_set_keybit, b'U', 101);
+    nix::ioctl_write_int!(ui_set_relbit, b'U', 102);
+    nix::ioctl_write_int!(ui_set_absbit, b'U', 103);
+    nix::ioctl_write_int!(ui_set_mscbit, b'U', 104);
+    nix::ioctl_write_int!(ui_set_ledbit, b'U', 105);
+    nix::ioctl_write_int!(ui_set_sndbit, b'U', 106);
+    nix::ioctl_write_int!(ui_set_swbit, b'U', 109);
+    nix::ioctl_write_int!(ui_set_propbit, b'U', 110);
+}
+
+pub struct UinputBuilder {
+    file: File,
+    id: libc::input_id,
+}
+
+impl UinputBuilder {
+    pub fn new(opener: &Opener) -> io::Result<Self> {
+        let file = File::from(opener.open(Path::new("/dev/uinput"))?);
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        Ok(Self {
+            file,
+            id: libc::input_id {
+                bustype: BusType::BUS_VIRTUAL.0,
+                vendor: 0,
+                product: 0,
+                version: 0,
+            },
+        })
+    }
+
+    /// Present the device as `id` rather than as a generic virtual device.
+    pub fn input_id(mut self, id: &InputId) -> Self {
+        self.id = libc::input_id {
+            bustype: id.bus_type().0,
+            vendor: id.vendor(),
+            product: id.product(),
+            version: id.version(),
+        };
+        self
+    }
+
+    /// Enable `event_type` with the given codes. Absolute axes need their
+    /// ranges, see `with_absolute_axis`.
+    pub fn with_events(
+        self,
+        event_type: EventType,
+        codes: impl IntoIterator<Item = u16>,
+    ) -> io::Result<Self> {
+        let set_bit = match event_type {
+            EventType::KEY => sys::ui_set_keybit,
+            EventType::RELATIVE => sys::ui_set_relbit,
+            EventType::MISC => sys::ui_set_mscbit,
+            EventType::LED => sys::ui_set_ledbit,
+            EventType::SOUND => sys::ui_set_sndbit,
+            EventType::SWITCH => sys::ui_set_swbit,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot enable {event_type:?} this way"),
+                ));
+            }
+        };
+        let fd = self.file.as_raw_fd();
+        let mut codes = codes.into_iter().peekable();
+        if codes.peek().is_none() {
+            return Ok(self);
+        }
+        // SAFETY: `fd` is an open uinput device that hasn't been created yet.
+        unsafe {
+            sys::ui_set_evbit(fd, event_type.0 as _)?;
+            for code in codes {
+                set_bit(fd, code as _)?;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_absolute_axis(self, code: u16, absinfo: libc::input_absinfo) -> io::Result<Self> {
+        let fd = self.file.as_raw_fd();
+        let setup = libc::uinput_abs_setup { code, absinfo };
+        // SAFETY: as above, and `setup` outlives the call.
+        unsafe {
+            sys::ui_set_evbit(fd, EventType::ABSOLUTE.0 as _)?;
+            sys::ui_set_absbit(fd, code as _)?;
+            sys::ui_abs_setup(fd, &setup)?;
+        }
+        Ok(self)
+    }
+
+    /// Copy every capability and property of `source`, except autorepeat,
+    /// which the source's own repeat events already cover, and force
+    /// feedback, which would need effect uploads forwarded too.
+    pub fn mirror(mut self, source: &RawDevice) -> io::Result<Self> {
+        if let Some(keys) = source.supported_keys() {
+            self = self.with_events(EventType::KEY, keys.iter().map(|key| key.code()))?;
+        }
+        if let Some(axes) = source.supported_relative_axes() {
+            self = self.with_events(EventType::RELATIVE, axes.iter().map(|axis| axis.0))?;
+        }
+        if let Some(axes) = source.supported_absolute_axes() {
+            let state = source.get_abs_state()?;
+            for axis in axes.iter() {
+                self = self.with_absolute_axis(axis.0, state[axis.0 as usize])?;
+            }
+        }
+        if let Some(misc) = source.misc_properties() {
+            self = self.with_events(EventType::MISC, misc.iter().map(|misc| misc.0))?;
+        }
+        if let Some(leds) = source.supported_leds() {
+            self = self.with_events(EventType::LED, leds.iter().map(|led| led.0))?;
+        }
+        if let Some(sounds) = source.supported_sounds() {
+            self = self.with_events(EventType::SOUND, sounds.iter().map(|sound| sound.0))?;
+        }
+        if let Some(switches) = source.supported_switches() {
+            self = self.with_events(EventType::SWITCH, switches.iter().map(|switch| switch.0))?;
+        }
+        let fd = self.file.as_raw_fd();
+        for prop in source.properties().iter() {
+            // SAFETY: as above.
+            unsafe { sys::ui_set_propbit(fd, prop.0 as _)? };
+        }
+        Ok(self)
+    }
+
+    pub fn build(self, name: &str) -> io::Result<UinputDevice> {
+        // SAFETY: uinput_setup is plain old data, all zeroes is valid.
+        let mut setup: libc::uinput_setup = unsafe { std::mem::zeroed() };
+        setup.id = self.id;
+        // Leave room for the terminating NUL the zeroing put there
+        for (dst, src) in setup
+            .name
+            .iter_mut()
+            .zip(name.bytes())
+            .take(libc::UINPUT_MAX_NAME_SIZE - 1)
+        {
+            *dst = src as libc::c_char;
+        }
+        let fd = self.file.as_raw_fd();
+        // SAFETY: as above, and `setup` outlives the call.
+        unsafe {
+            sys::ui_dev_setup(fd, &setup)?;
+            sys::ui_dev_create(fd)?;
+        }
+        Ok(UinputDevice {
+            feedback: Feedback::Unregistered,
+            file: self.file,
+        })
+    }
+}
+
+/// A created uinput device; destroyed when dropped.
+pub struct UinputDevice {
+    // Declared first so it is deregistered before the file is closed
+    feedback: Feedback,
+    file: File,
+}
+
+enum Feedback {
+    Unregistered,
+    Registered(AsyncFd<RawFd>),
+    Failed,
+}
+
+impl UinputDevice {
+    /// Emit `events` as one frame; the SYN_REPORT is appended here.
+    pub fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let syn = InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0);
+        self.file.write_all(as_bytes(events))?;
+        self.file.write_all(as_bytes(&[syn]))
+    }
+
+    /// The next event written to the device from the other side, such as the
+    /// compositor switching the CapsLock LED on. Registers with the runtime it
+    /// is first awaited on; after an error it is pending forever.
+    pub async fn next_feedback(&mut self) -> io::Result<InputEvent> {
+        if let Feedback::Unregistered = self.feedback {
+            self.feedback = match AsyncFd::with_interest(self.file.as_raw_fd(), Interest::READABLE)
+            {
+                Ok(fd) => Feedback::Registered(fd),
+                Err(e) => {
+                    self.feedback = Feedback::Failed;
+                    return Err(e);
+                }
+            };
+        }
+        let Feedback::Registered(fd) = &self.feedback else {
+            return std::future::pending().await;
+        };
+        let result = loop {
+            let mut guard = match fd.readable().await {
+                Ok(guard) => guard,
+                Err(e) => break Err(e),
+            };
+            if let Ok(result) = guard.try_io(|fd| read_event(*fd.get_ref())) {
+                break result;
+            }
+        };
+        if result.is_err() {
+            self.feedback = Feedback::Failed;
+        }
+        result
+    }
+}
+
+fn read_event(fd: RawFd) -> io::Result<InputEvent> {
+    // SAFETY: input_event is plain old data, all zeroes is valid.
+    let mut raw: libc::input_event = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::input_event>();
+    // SAFETY: `raw` is `size` writable bytes.
+    let read = unsafe { libc::read(fd, (&raw mut raw).cast(), size) };
+    match read {
+        ..0 => Err(io::Error::last_os_error()),
+        n if n as usize == size => Ok(InputEvent::from(raw)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "short read from uinput",
+        )),
+    }
+}
+
+fn as_bytes(events: &[InputEvent]) -> &[u8] {
+    // SAFETY: InputEvent is a repr(transparent) wrapper around input_event,
+    // which is plain old data.
+    unsafe { std::slice::from_raw_parts(events.as_ptr().cast(), std::mem::size_of_val(events)) }
+}
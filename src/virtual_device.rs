@@ -1,42 +1,322 @@
-use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
-use evdev::{AttributeSet, Device, InputEvent, Key};
-use tracing::info;
+use crate::config::{WHEEL_DOWN, WHEEL_LEFT, WHEEL_RIGHT, WHEEL_UP};
+use crate::helper::Opener;
+use crate::uinput::{UinputBuilder, UinputDevice};
+use evdev::raw_stream::RawDevice;
+use evdev::{EventType, InputEvent, KeyCode, RelativeAxisCode, SynchronizationCode};
+use std::collections::HashSet;
+use tracing::{info, warn};
 
-pub fn create_virtual_keyboard(source: &Device) -> std::io::Result<VirtualDevice> {
-    let mut builder = VirtualDeviceBuilder::new()?.name("splash-damage virtual keyboard");
+/// Units of `REL_WHEEL_HI_RES` per wheel notch.
+pub const HI_RES_PER_NOTCH: i32 = 120;
 
-    if let Some(keys) = source.supported_keys() {
-        let mut key_set = AttributeSet::<Key>::new();
-        for key in keys.iter() {
-            key_set.insert(key);
+const POINTER_AXES: [RelativeAxisCode; 6] = [
+    RelativeAxisCode::REL_X,
+    RelativeAxisCode::REL_Y,
+    RelativeAxisCode::REL_WHEEL,
+    RelativeAxisCode::REL_HWHEEL,
+    RelativeAxisCode::REL_WHEEL_HI_RES,
+    RelativeAxisCode::REL_HWHEEL_HI_RES,
+];
+
+/// A virtual keyboard that knows exactly which keys it currently has down.
+pub struct VirtualKeyboard {
+    device: UinputDevice,
+    down: HashSet<KeyCode>,
+}
+
+/// Mirrors everything `source` can do, so the LEDs, scan codes and any
+/// trackpoint or touchpad on the same node keep working. `pointer` also
+/// makes it a mouse, for the buttons and wheel that grabbed pointer devices
+/// hand over to be remapped. `copy_id` presents it with the source's bus,
+/// vendor and product.
+pub fn create_virtual_keyboard(
+    opener: &Opener,
+    source: &RawDevice,
+    pointer: bool,
+    copy_id: bool,
+) -> std::io::Result<VirtualKeyboard> {
+    let mut builder = UinputBuilder::new(opener)?.mirror(source)?;
+    if copy_id {
+        builder = builder.input_id(&source.input_id());
+    }
+    if pointer {
+        builder = builder
+            .with_events(
+                EventType::KEY,
+                KeyCode::BTN_LEFT.code()..=KeyCode::BTN_TASK.code(),
+            )?
+            .with_events(EventType::RELATIVE, POINTER_AXES.map(|axis| axis.0))?;
+    }
+
+    let name = virtual_name(source, "");
+    let virt = builder.build(&name)?;
+    info!("created virtual keyboard {name:?}");
+    Ok(VirtualKeyboard {
+        device: virt,
+        down: HashSet::new(),
+    })
+}
+
+/// A plain three-button mouse with a wheel, for mouse keys on `source`.
+pub fn create_virtual_mouse(
+    opener: &Opener,
+    source: &RawDevice,
+) -> std::io::Result<VirtualKeyboard> {
+    let name = virtual_name(source, " mouse keys");
+    let buttons = [KeyCode::BTN_LEFT, KeyCode::BTN_RIGHT, KeyCode::BTN_MIDDLE];
+    let virt = UinputBuilder::new(opener)?
+        .with_events(EventType::KEY, buttons.map(|button| button.code()))?
+        .with_events(EventType::RELATIVE, POINTER_AXES.map(|axis| axis.0))?
+        .build(&name)?;
+    info!("created virtual mouse {name:?}");
+    Ok(VirtualKeyboard {
+        device: virt,
+        down: HashSet::new(),
+    })
+}
+
+/// A pass-through copy of a grabbed pointer device.
+pub fn create_virtual_pointer(
+    opener: &Opener,
+    source: &RawDevice,
+    copy_id: bool,
+) -> std::io::Result<VirtualKeyboard> {
+    let mut builder = UinputBuilder::new(opener)?.mirror(source)?;
+    if copy_id {
+        builder = builder.input_id(&source.input_id());
+    }
+    let name = virtual_name(source, "");
+    let virt = builder.build(&name)?;
+    info!("created virtual pointer {name:?}");
+    Ok(VirtualKeyboard {
+        device: virt,
+        down: HashSet::new(),
+    })
+}
+
+/// "splash-damage: <source name>", which `device` also relies on to never
+/// grab our own devices.
+fn virtual_name(source: &RawDevice, suffix: &str) -> String {
+    format!(
+        "splash-damage: {}{suffix}",
+        source.name().unwrap_or("unknown")
+    )
+}
+
+impl VirtualKeyboard {
+    pub fn release_all_modifiers(&mut self) -> std::io::Result<()> {
+        let modifiers = [
+            KeyCode::KEY_LEFTCTRL,
+            KeyCode::KEY_RIGHTCTRL,
+            KeyCode::KEY_LEFTSHIFT,
+            KeyCode::KEY_RIGHTSHIFT,
+            KeyCode::KEY_LEFTALT,
+            KeyCode::KEY_RIGHTALT,
+            KeyCode::KEY_LEFTMETA,
+            KeyCode::KEY_RIGHTMETA,
+        ];
+        let events: Vec<InputEvent> = modifiers
+            .iter()
+            .map(|k| InputEvent::new(EventType::KEY.0, k.code(), 0))
+            .collect();
+        self.emit(&events)
+    }
+
+    /// Emit `events` as one frame; the SYN_REPORT is appended here. Wheel
+    /// notches end their frame early, see `wheel_to_rel`.
+    pub fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        if events.iter().any(|e| wheel_notch(e).is_some()) {
+            self.device.emit(&wheel_to_rel(events))?;
+        } else {
+            self.device.emit(events)?;
+        }
+        for event in events {
+            if event.event_type() != EventType::KEY || wheel_notch(event).is_some() {
+                continue;
+            }
+            let key = KeyCode::new(event.code());
+            match event.value() {
+                0 => {
+                    self.down.remove(&key);
+                }
+                1 => {
+                    self.down.insert(key);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The next LED or sound event written to the device, to pass on to
+    /// the physical one.
+    pub async fn next_feedback(&mut self) -> std::io::Result<InputEvent> {
+        self.device.next_feedback().await
+    }
+
+    pub fn keys_down(&self) -> &HashSet<KeyCode> {
+        &self.down
+    }
+
+    /// Release every key this device has down.
+    pub fn release_all(&mut self) -> std::io::Result<()> {
+        if self.down.is_empty() {
+            return Ok(());
+        }
+        let events: Vec<InputEvent> = self
+            .down
+            .iter()
+            .map(|k| InputEvent::new(EventType::KEY.0, k.code(), 0))
+            .collect();
+        info!("releasing {} held virtual keys", self.down.len());
+        self.emit(&events)
+    }
+}
+
+/// The axis and direction a wheel pseudo-key event scrolls, if it is one.
+fn wheel_notch(event: &InputEvent) -> Option<(RelativeAxisCode, RelativeAxisCode, i32)> {
+    if event.event_type() != EventType::KEY {
+        return None;
+    }
+    let vertical = (
+        RelativeAxisCode::REL_WHEEL,
+        RelativeAxisCode::REL_WHEEL_HI_RES,
+    );
+    let horizontal = (
+        RelativeAxisCode::REL_HWHEEL,
+        RelativeAxisCode::REL_HWHEEL_HI_RES,
+    );
+    let ((axis, hi_res), direction) = match KeyCode::new(event.code()) {
+        WHEEL_UP => (vertical, 1),
+        WHEEL_DOWN => (vertical, -1),
+        WHEEL_RIGHT => (horizontal, 1),
+        WHEEL_LEFT => (horizontal, -1),
+        _ => return None,
+    };
+    Some((axis, hi_res, direction))
+}
+
+/// Turn wheel pseudo-key presses back into scroll events; their releases
+/// have nothing to undo. Keys reach clients as soon as they are read but
+/// scrolling only at the end of the frame, so each notch ends its frame:
+/// otherwise `super+wheelup → ctrl+wheelup` would let go of ctrl before
+/// the scroll it was held for arrived.
+fn wheel_to_rel(events: &[InputEvent]) -> Vec<InputEvent> {
+    let syn_report = InputEvent::new(
+        EventType::SYNCHRONIZATION.0,
+        SynchronizationCode::SYN_REPORT.0,
+        0,
+    );
+    let mut out = Vec::with_capacity(events.len() + 2);
+    for event in events {
+        match wheel_notch(event) {
+            Some((axis, hi_res, direction)) if event.value() == 1 => {
+                out.push(InputEvent::new(EventType::RELATIVE.0, axis.0, direction));
+                out.push(InputEvent::new(
+                    EventType::RELATIVE.0,
+                    hi_res.0,
+                    direction * HI_RES_PER_NOTCH,
+                ));
+                out.push(syn_report);
+            }
+            Some(_) => {}
+            None => out.push(*event),
         }
-        builder = builder.with_keys(&key_set)?;
-    }
-
-    let virt = builder.build()?;
-    info!("created virtual keyboard device");
-    Ok(virt)
-}
-
-pub fn release_all_modifiers(virt: &mut VirtualDevice) -> std::io::Result<()> {
-    let modifiers = [
-        Key::KEY_LEFTCTRL,
-        Key::KEY_RIGHTCTRL,
-        Key::KEY_LEFTSHIFT,
-        Key::KEY_RIGHTSHIFT,
-        Key::KEY_LEFTALT,
-        Key::KEY_RIGHTALT,
-        Key::KEY_LEFTMETA,
-        Key::KEY_RIGHTMETA,
-    ];
-    let mut events: Vec<InputEvent> = modifiers
-        .iter()
-        .map(|k| InputEvent::new(evdev::EventType::KEY, k.code(), 0))
-        .collect();
-    events.push(InputEvent::new(evdev::EventType::SYNCHRONIZATION, 0, 0));
-    virt.emit(&events)
-}
-
-pub fn emit_events(virt: &mut VirtualDevice, events: &[InputEvent]) -> std::io::Result<()> {
-    virt.emit(events)
+    }
+    // The device ends the last frame itself
+    if out.last() == Some(&syn_report) {
+        out.pop();
+    }
+    out
+}
+
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        if let Err(e) = self.release_all() {
+            warn!("failed to release held keys: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rel(axis: RelativeAxisCode, value: i32) -> InputEvent {
+        InputEvent::new(EventType::RELATIVE.0, axis.0, value)
+    }
+
+    fn key(key: KeyCode, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, key.code(), value)
+    }
+
+    /// `events` as the frames the device emits, split at each SYN_REPORT.
+    fn frames(events: &[InputEvent]) -> Vec<Vec<(EventType, u16, i32)>> {
+        wheel_to_rel(events)
+            .split(|e| e.event_type() == EventType::SYNCHRONIZATION)
+            .map(|frame| {
+                frame
+                    .iter()
+                    .map(|e| (e.event_type(), e.code(), e.value()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn summary(events: &[InputEvent]) -> Vec<(EventType, u16, i32)> {
+        events
+            .iter()
+            .map(|e| (e.event_type(), e.code(), e.value()))
+            .collect()
+    }
+
+    #[test]
+    fn wheel_presses_become_notches_and_releases_vanish() {
+        let ctrl = key(KeyCode::KEY_LEFTCTRL, 1);
+        let events = [
+            ctrl,
+            key(WHEEL_DOWN, 1),
+            key(WHEEL_DOWN, 0),
+            key(WHEEL_RIGHT, 1),
+        ];
+        assert_eq!(
+            frames(&events),
+            [
+                summary(&[
+                    ctrl,
+                    rel(RelativeAxisCode::REL_WHEEL, -1),
+                    rel(RelativeAxisCode::REL_WHEEL_HI_RES, -HI_RES_PER_NOTCH),
+                ]),
+                summary(&[
+                    rel(RelativeAxisCode::REL_HWHEEL, 1),
+                    rel(RelativeAxisCode::REL_HWHEEL_HI_RES, HI_RES_PER_NOTCH),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn modifiers_let_go_after_a_notch_come_in_the_next_frame() {
+        // super+wheelup → ctrl+wheelup, press and release handed over together
+        let events = [
+            key(KeyCode::KEY_LEFTMETA, 0),
+            key(KeyCode::KEY_LEFTCTRL, 1),
+            key(WHEEL_UP, 1),
+            key(WHEEL_UP, 0),
+            key(KeyCode::KEY_LEFTCTRL, 0),
+            key(KeyCode::KEY_LEFTMETA, 1),
+        ];
+        assert_eq!(
+            frames(&events),
+            [
+                summary(&[
+                    key(KeyCode::KEY_LEFTMETA, 0),
+                    key(KeyCode::KEY_LEFTCTRL, 1),
+                    rel(RelativeAxisCode::REL_WHEEL, 1),
+                    rel(RelativeAxisCode::REL_WHEEL_HI_RES, HI_RES_PER_NOTCH),
+                ]),
+                summary(&[key(KeyCode::KEY_LEFTCTRL, 0), key(KeyCode::KEY_LEFTMETA, 1),]),
+            ]
+        );
+    }
 }
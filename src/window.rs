@@ -1,27 +1,51 @@
+use crate::config::LoadedConfig;
+use crate::control::Event;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{info, warn};
+use tokio::sync::{RwLock, broadcast, watch};
+use tracing::{debug, info, warn};
 use zbus::proxy;
 
 const KWIN_SCRIPT: &str = r#"
-function notifyActiveWindow(window) {
+function notify(method, window) {
     if (window) {
         callDBus(
             "com.splashdamage.ActiveWindow",
             "/active_window",
             "com.splashdamage.ActiveWindow",
-            "NotifyActiveWindow",
+            method,
             window.resourceClass || "",
             window.caption || ""
         );
     }
 }
 
-workspace.windowActivated.connect(notifyActiveWindow);
+// Titles change under a focused window too, e.g. a terminal running sudo
+var watched = null;
+
+function notifyCaptionChanged() {
+    if (watched && workspace.activeWindow === watched) {
+        notify("NotifyCaptionChanged", watched);
+    }
+}
+
+function windowActivated(window) {
+    if (watched) {
+        try {
+            watched.captionChanged.disconnect(notifyCaptionChanged);
+        } catch (e) {}
+    }
+    watched = window;
+    if (window) {
+        window.captionChanged.connect(notifyCaptionChanged);
+    }
+    notify("NotifyActiveWindow", window);
+}
+
+workspace.windowActivated.connect(windowActivated);
 
 var current = workspace.activeWindow;
 if (current) {
-    notifyActiveWindow(current);
+    windowActivated(current);
 }
 "#;
 
@@ -45,7 +69,10 @@ trait KWinScript {
 
 #[derive(Debug, Clone)]
 pub struct ActiveWindow {
-    pub resource_class: String,
+    pub resource_class: Arc<str>,
+    pub caption: Arc<str>,
+    /// Counts window activations, and stays put when only the title changes
+    pub activation: u64,
 }
 
 pub type SharedActiveWindow = Arc<RwLock<Option<ActiveWindow>>>;
@@ -56,20 +83,62 @@ pub fn shared_active_window() -> SharedActiveWindow {
 
 struct ActiveWindowService {
     state: SharedActiveWindow,
+    events: broadcast::Sender<Event>,
+    config: watch::Receiver<Arc<LoadedConfig>>,
 }
 
 #[zbus::interface(name = "com.splashdamage.ActiveWindow")]
 impl ActiveWindowService {
     async fn notify_active_window(&self, resource_class: &str, caption: &str) {
-        let mut state = self.state.write().await;
-        *state = Some(ActiveWindow {
+        self.update(resource_class, caption, true).await;
+    }
+
+    async fn notify_caption_changed(&self, resource_class: &str, caption: &str) {
+        self.update(resource_class, caption, false).await;
+    }
+}
+
+impl ActiveWindowService {
+    /// Record the active window, which `activated` says is newly focused
+    /// rather than just retitled.
+    async fn update(&self, resource_class: &str, caption: &str, activated: bool) {
+        let same_class = {
+            let mut state = self.state.write().await;
+            let same_class = state
+                .as_ref()
+                .is_some_and(|w| &*w.resource_class == resource_class);
+            let activation = state.as_ref().map_or(0, |w| w.activation);
+            *state = Some(ActiveWindow {
+                resource_class: resource_class.into(),
+                caption: caption.into(),
+                activation: activation + u64::from(activated),
+            });
+            same_class
+        };
+        // A password prompt's title can say what it is for, such as the
+        // command a terminal is running under sudo
+        let secure = self
+            .config
+            .borrow()
+            .secure_input
+            .matches(resource_class, caption);
+        let caption = if secure { "" } else { caption };
+        if secure {
+            info!(resource_class, "secure-input window focused");
+        } else if same_class {
+            // Terminals retitle themselves with every command; keep that out of info
+            debug!(resource_class, caption, "active window changed");
+        } else {
+            info!(resource_class, caption, "active window changed");
+        }
+        let _ = self.events.send(Event::ActiveWindow {
             resource_class: resource_class.to_string(),
+            caption: caption.to_string(),
         });
-        info!(resource_class, caption, "active window changed");
     }
 }
 
-fn find_session_bus_address() -> anyhow::Result<String> {
+pub fn find_session_bus_address() -> anyhow::Result<String> {
     if let Ok(addr) = std::env::var("DBUS_SESSION_BUS_ADDRESS") {
         return Ok(addr);
     }
@@ -86,9 +155,11 @@ fn find_session_bus_address() -> anyhow::Result<String> {
 pub struct WindowWatcher {
     pub connection: zbus::Connection,
     pub script_id: i32,
+    pub script_path: std::path::PathBuf,
 }
 
 impl WindowWatcher {
+    /// Stop the KWin script and remove the temporary file it was loaded from.
     pub async fn stop_script(&self) {
         let path = format!("/Scripting/Script{}", self.script_id);
         if let Ok(proxy) = KWinScriptProxy::builder(&self.connection)
@@ -100,10 +171,21 @@ impl WindowWatcher {
             let _ = proxy.stop().await;
             info!("stopped kwin script {}", self.script_id);
         }
+
+        if let Err(e) = std::fs::remove_file(&self.script_path) {
+            warn!("failed to remove {}: {e}", self.script_path.display());
+        }
+        if let Some(dir) = self.script_path.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
     }
 }
 
-pub async fn start_window_watcher(state: SharedActiveWindow) -> anyhow::Result<WindowWatcher> {
+pub async fn start_window_watcher(
+    state: SharedActiveWindow,
+    events: broadcast::Sender<Event>,
+    config: watch::Receiver<Arc<LoadedConfig>>,
+) -> anyhow::Result<WindowWatcher> {
     let bus_addr = find_session_bus_address()?;
     info!("connecting to session bus at {bus_addr}");
 
@@ -113,7 +195,14 @@ pub async fn start_window_watcher(state: SharedActiveWindow) -> anyhow::Result<W
 
     session
         .object_server()
-        .at("/active_window", ActiveWindowService { state })
+        .at(
+            "/active_window",
+            ActiveWindowService {
+                state,
+                events,
+                config,
+            },
+        )
         .await?;
 
     session
@@ -146,6 +235,7 @@ pub async fn start_window_watcher(state: SharedActiveWindow) -> anyhow::Result<W
     Ok(WindowWatcher {
         connection: session,
         script_id,
+        script_path,
     })
 }
 
@@ -184,8 +274,13 @@ async fn load_kwin_script(
     Ok(script_id)
 }
 
+/// Where the KWin script is written for KWin to load.
+pub fn script_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join("splash-damage")
+}
+
 fn write_kwin_script() -> anyhow::Result<std::path::PathBuf> {
-    let dir = std::env::temp_dir().join("splash-damage");
+    let dir = script_dir();
     std::fs::create_dir_all(&dir)?;
     let path = dir.join("active_window.js");
     std::fs::write(&path, KWIN_SCRIPT)?;